@@ -0,0 +1,164 @@
+use crate::protocol::{
+    decode_payload, encode_range_request, encode_trace, is_supported_version, parse_hello,
+    parse_inodes, Frame, Opcode, FEATURE_ZSTD,
+};
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Blocking connection to the indexer speaking the framed protocol
+pub struct IndexerClient {
+    stream: TcpStream,
+    next_request_id: u32,
+    /// Features enabled for this connection by the handshake
+    features: u32,
+}
+
+impl IndexerClient {
+    /// Connect to the indexer and perform the version handshake. `timeout`
+    /// bounds the connect and every later read or write on the connection,
+    /// and `compression` asks the indexer to compress the data it sends.
+    pub fn connect(tcp_addr: &str, timeout: Duration, compression: bool) -> io::Result<Self> {
+        let addr = tcp_addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Failed to resolve {}", tcp_addr),
+            )
+        })?;
+
+        let stream = TcpStream::connect_timeout(&addr, timeout).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("Failed to connect to {}: {}", tcp_addr, e),
+            )
+        })?;
+
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        stream
+            .set_nodelay(true)
+            .map_err(|e| io::Error::other(format!("Failed to set TCP_NODELAY: {}", e)))?;
+
+        let mut client = IndexerClient {
+            stream,
+            next_request_id: 0,
+            features: 0,
+        };
+
+        let features = if compression { FEATURE_ZSTD } else { 0 };
+        client.handshake(features)?;
+
+        Ok(client)
+    }
+
+    /// Announce our protocol version and the features we want, and check the
+    /// version and features the indexer answers with
+    fn handshake(&mut self, features: u32) -> io::Result<()> {
+        let request_id = self.next_id();
+        Frame::hello(request_id, features).write_to(&mut self.stream)?;
+
+        let reply = Frame::read_from(&mut self.stream)?;
+        let (version, enabled) = parse_hello(&reply.into_payload(Opcode::Hello)?)?;
+
+        if !is_supported_version(version) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Indexer speaks unsupported protocol version {}", version),
+            ));
+        }
+
+        if enabled & !features != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Indexer enabled features {:#x} that were not asked for",
+                    enabled
+                ),
+            ));
+        }
+        self.features = enabled;
+
+        log::debug!(
+            "Connected to indexer with protocol version {} and features {:#x}",
+            version,
+            enabled
+        );
+        Ok(())
+    }
+
+    fn next_id(&mut self) -> u32 {
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+        self.next_request_id
+    }
+
+    /// Send a request and wait for its response payload
+    fn request(
+        &mut self,
+        opcode: Opcode,
+        payload: Vec<u8>,
+        expected: Opcode,
+    ) -> io::Result<Vec<u8>> {
+        let request_id = self.next_id();
+        Frame::new(opcode, request_id, payload).write_to(&mut self.stream)?;
+
+        let reply = Frame::read_from(&mut self.stream)?;
+        if reply.request_id != request_id {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Response for request {} received while waiting for {}",
+                    reply.request_id, request_id
+                ),
+            ));
+        }
+
+        let payload = reply.into_payload(expected)?;
+        match expected {
+            Opcode::ImageData | Opcode::Content | Opcode::Range
+                if self.features & FEATURE_ZSTD != 0 =>
+            {
+                decode_payload(&payload, expected.max_decoded_len())
+            }
+            _ => Ok(payload),
+        }
+    }
+
+    /// Fetch the serialized metadata of an image
+    pub fn get_image_data(&mut self, image_name: &str) -> io::Result<Vec<u8>> {
+        self.request(
+            Opcode::GetImageData,
+            image_name.as_bytes().to_vec(),
+            Opcode::ImageData,
+        )
+    }
+
+    /// Fetch up to `length` bytes of a blob starting at `offset`
+    pub fn read_range(&mut self, hash: &str, offset: u64, length: u32) -> io::Result<Vec<u8>> {
+        self.request(
+            Opcode::ReadRange,
+            encode_range_request(hash, offset, length),
+            Opcode::Range,
+        )
+    }
+
+    /// Store the access trace recorded for an image
+    pub fn put_trace(&mut self, image_name: &str, inodes: &[u64]) -> io::Result<()> {
+        self.request(
+            Opcode::PutTrace,
+            encode_trace(image_name, inodes)?,
+            Opcode::TraceSaved,
+        )?;
+        Ok(())
+    }
+
+    /// Fetch the access trace recorded for an image
+    pub fn get_trace(&mut self, image_name: &str) -> io::Result<Vec<u64>> {
+        let payload = self.request(
+            Opcode::GetTrace,
+            image_name.as_bytes().to_vec(),
+            Opcode::Trace,
+        )?;
+        parse_inodes(&payload)
+    }
+}
@@ -0,0 +1,468 @@
+use crate::protocol::MAX_RANGE_LEN;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Reads bump the mtime of a blob at most this often, it orders blobs for
+/// eviction when the cache is opened again
+const MTIME_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Settings for the on-disk content cache
+#[derive(Debug, Clone)]
+pub struct DiskCacheConfig {
+    /// Directory holding the cached blobs
+    pub path: PathBuf,
+    /// Total size the cache may grow to before evicting blobs
+    pub max_bytes: u64,
+    /// Blobs up to this size are fetched whole and persisted, larger ones are
+    /// only read block by block
+    pub max_blob_size: u64,
+}
+
+struct Entry {
+    size: u64,
+    last_used: u64,
+    verified: bool,
+    modified: SystemTime,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, Entry>,
+    lru: BTreeMap<u64, String>,
+    total_bytes: u64,
+    clock: u64,
+}
+
+impl CacheState {
+    fn touch(&mut self, hash: &str) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(entry) = self.entries.get_mut(hash) {
+            self.lru.remove(&entry.last_used);
+            entry.last_used = clock;
+            self.lru.insert(clock, hash.to_string());
+        }
+    }
+
+    fn insert(&mut self, hash: &str, size: u64, verified: bool, modified: SystemTime) {
+        self.remove(hash);
+        self.clock += 1;
+        self.entries.insert(
+            hash.to_string(),
+            Entry {
+                size,
+                last_used: self.clock,
+                verified,
+                modified,
+            },
+        );
+        self.lru.insert(self.clock, hash.to_string());
+        self.total_bytes += size;
+    }
+
+    fn remove(&mut self, hash: &str) -> bool {
+        match self.entries.remove(hash) {
+            Some(entry) => {
+                self.lru.remove(&entry.last_used);
+                self.total_bytes -= entry.size;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Content-addressed cache of blobs on the silo host, keyed by the SHA-256
+/// the indexer assigns. Blobs are evicted least recently used first once the
+/// cache grows past its limit, and re-hashed the first time they are read
+/// after a restart.
+pub struct DiskCache {
+    config: DiskCacheConfig,
+    state: Mutex<CacheState>,
+}
+
+impl DiskCache {
+    /// Open the cache, picking up the blobs left by a previous run
+    pub fn open(mut config: DiskCacheConfig) -> io::Result<Self> {
+        fs::create_dir_all(&config.path)?;
+
+        // Persisted blobs are fetched in a single ranged read
+        config.max_blob_size = config.max_blob_size.min(MAX_RANGE_LEN as u64);
+
+        let mut blobs = Vec::new();
+        for entry in fs::read_dir(&config.path)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let metadata = entry.metadata()?;
+
+            if name.starts_with("tmp-") {
+                // Leftover of an interrupted write
+                remove_blob(&entry.path())?;
+                continue;
+            }
+
+            if !is_hash(&name) || !metadata.is_file() {
+                continue;
+            }
+
+            let mtime = metadata.modified().unwrap_or(UNIX_EPOCH);
+            blobs.push((mtime, name, metadata.len()));
+        }
+
+        // Oldest blobs first so they are the first to be evicted
+        blobs.sort();
+
+        let mut state = CacheState::default();
+        for (mtime, hash, size) in blobs {
+            state.insert(&hash, size, false, mtime);
+        }
+
+        log::info!(
+            "Loaded {} blobs ({} bytes) from disk cache at {}",
+            state.entries.len(),
+            state.total_bytes,
+            config.path.display()
+        );
+
+        let cache = Self {
+            config,
+            state: Mutex::new(state),
+        };
+        cache.evict(&mut *cache.lock_state()?)?;
+
+        Ok(cache)
+    }
+
+    /// Largest blob that gets persisted
+    pub fn max_blob_size(&self) -> u64 {
+        self.config.max_blob_size
+    }
+
+    /// Read up to `length` bytes at `offset` from a cached blob, or `None` if
+    /// the blob is not cached or failed verification
+    pub fn read(&self, hash: &str, offset: u64, length: usize) -> io::Result<Option<Vec<u8>>> {
+        let now = SystemTime::now();
+        let (verified, bump_mtime) = {
+            let mut state = self.lock_state()?;
+            let (verified, bump_mtime) = match state.entries.get_mut(hash) {
+                Some(entry) => {
+                    let bump_mtime = now
+                        .duration_since(entry.modified)
+                        .is_ok_and(|age| age >= MTIME_INTERVAL);
+                    if bump_mtime {
+                        entry.modified = now;
+                    }
+                    (entry.verified, bump_mtime)
+                }
+                None => return Ok(None),
+            };
+            state.touch(hash);
+            (verified, bump_mtime)
+        };
+
+        let path = self.blob_path(hash);
+
+        if !verified {
+            if !verify_file(&path, hash)? {
+                log::warn!("Cached blob {} failed verification, discarding it", hash);
+                self.remove(hash)?;
+                return Ok(None);
+            }
+
+            if let Some(entry) = self.lock_state()?.entries.get_mut(hash) {
+                entry.verified = true;
+            }
+        }
+
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            // Evicted between the lookup and the open
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        // Keep the order of use across restarts
+        if bump_mtime {
+            if let Err(e) = file.set_modified(now) {
+                log::debug!("Failed to update mtime of cached blob {}: {}", hash, e);
+            }
+        }
+
+        let mut data = vec![0u8; length];
+        let mut read = 0;
+        while read < length {
+            match file.read_at(&mut data[read..], offset + read as u64)? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        data.truncate(read);
+
+        Ok(Some(data))
+    }
+
+    /// Persist a blob after checking that it matches its hash
+    pub fn insert(&self, hash: &str, content: &[u8]) -> io::Result<()> {
+        if !is_hash(hash) || hash_bytes(content) != hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Content does not match hash {}", hash),
+            ));
+        }
+
+        let size = content.len() as u64;
+        if size > self.config.max_blob_size || size > self.config.max_bytes {
+            return Ok(());
+        }
+
+        // Write under a temporary name so a crash never leaves a partial blob
+        let tmp_path = self.tmp_path(hash);
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(content)?;
+        file.sync_data()?;
+        fs::rename(&tmp_path, self.blob_path(hash))?;
+
+        let mut state = self.lock_state()?;
+        state.insert(hash, size, true, SystemTime::now());
+        self.evict(&mut state)
+    }
+
+    fn remove(&self, hash: &str) -> io::Result<()> {
+        if self.lock_state()?.remove(hash) {
+            remove_blob(&self.blob_path(hash))?;
+        }
+        Ok(())
+    }
+
+    /// Drop least recently used blobs until the cache fits its limit
+    fn evict(&self, state: &mut CacheState) -> io::Result<()> {
+        while state.total_bytes > self.config.max_bytes {
+            let hash = match state.lru.first_key_value() {
+                Some((_, hash)) => hash.clone(),
+                None => break,
+            };
+            state.remove(&hash);
+            remove_blob(&self.blob_path(&hash))?;
+            log::debug!("Evicted {} from disk cache", hash);
+        }
+        Ok(())
+    }
+
+    fn blob_path(&self, hash: &str) -> PathBuf {
+        self.config.path.join(hash)
+    }
+
+    fn tmp_path(&self, hash: &str) -> PathBuf {
+        self.config
+            .path
+            .join(format!("tmp-{}-{}", hash, rand::random::<u32>()))
+    }
+
+    fn lock_state(&self) -> io::Result<MutexGuard<'_, CacheState>> {
+        self.state
+            .lock()
+            .map_err(|e| io::Error::other(format!("Failed to lock disk cache: {}", e)))
+    }
+}
+
+fn remove_blob(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Whether a name looks like a hex-encoded SHA-256
+fn is_hash(name: &str) -> bool {
+    name.len() == 64 && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Hex-encoded SHA-256 of a blob
+pub fn hash_bytes(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
+/// Re-hash a file on disk without loading it whole
+fn verify_file(path: &Path, hash: &str) -> io::Result<bool> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        match file.read(&mut buf)? {
+            0 => break,
+            n => hasher.update(&buf[..n]),
+        }
+    }
+
+    Ok(format!("{:x}", hasher.finalize()) == hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("silo-disk-cache-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn open(path: &Path, max_bytes: u64) -> DiskCache {
+        DiskCache::open(DiskCacheConfig {
+            path: path.to_path_buf(),
+            max_bytes,
+            max_blob_size: 1024,
+        })
+        .unwrap()
+    }
+
+    fn blob(byte: u8) -> (String, Vec<u8>) {
+        let content = vec![byte; 100];
+        (hash_bytes(&content), content)
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let dir = temp_dir("lru");
+        let cache = open(&dir, 300);
+        let [a, b, c, d] = [1, 2, 3, 4].map(blob);
+
+        for (hash, content) in [&a, &b, &c] {
+            cache.insert(hash, content).unwrap();
+        }
+        // Reading `a` makes `b` the least recently used
+        assert!(cache.read(&a.0, 0, 100).unwrap().is_some());
+        cache.insert(&d.0, &d.1).unwrap();
+
+        assert!(cache.read(&b.0, 0, 100).unwrap().is_none());
+        assert!(!dir.join(&b.0).exists());
+        for (hash, content) in [&a, &c, &d] {
+            assert_eq!(cache.read(hash, 0, 100).unwrap().as_ref(), Some(content));
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reads_ranges() {
+        let dir = temp_dir("ranges");
+        let cache = open(&dir, 1000);
+        let content: Vec<u8> = (0..100).collect();
+        let hash = hash_bytes(&content);
+        cache.insert(&hash, &content).unwrap();
+
+        assert_eq!(
+            cache.read(&hash, 10, 5).unwrap().unwrap(),
+            [10, 11, 12, 13, 14]
+        );
+        // Cut short at the end of the blob
+        assert_eq!(cache.read(&hash, 98, 10).unwrap().unwrap(), [98, 99]);
+        assert!(cache.read(&hash, 200, 10).unwrap().unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_content_not_matching_hash() {
+        let dir = temp_dir("mismatch");
+        let cache = open(&dir, 1000);
+        let (hash, _) = blob(1);
+
+        let err = cache.insert(&hash, &[2; 100]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(file_names(&dir).is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recovers_after_restart() {
+        let dir = temp_dir("restart");
+        let [good, corrupt] = [1, 2].map(blob);
+        {
+            let cache = open(&dir, 1000);
+            cache.insert(&good.0, &good.1).unwrap();
+            cache.insert(&corrupt.0, &corrupt.1).unwrap();
+        }
+        fs::write(dir.join(&corrupt.0), [0; 100]).unwrap();
+        fs::write(dir.join(format!("tmp-{}-1", good.0)), [1; 10]).unwrap();
+        fs::write(dir.join("unrelated"), [1; 10]).unwrap();
+
+        let cache = open(&dir, 1000);
+        // Interrupted writes are cleaned up, files that are not blobs left
+        let mut expected = vec![corrupt.0.clone(), good.0.clone(), String::from("unrelated")];
+        expected.sort();
+        assert_eq!(file_names(&dir), expected);
+
+        assert_eq!(cache.read(&good.0, 0, 100).unwrap(), Some(good.1));
+        // Re-hashed on first read and discarded
+        assert!(cache.read(&corrupt.0, 0, 100).unwrap().is_none());
+        assert!(!dir.join(&corrupt.0).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn set_mtime(path: &Path, mtime: SystemTime) {
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+    }
+
+    #[test]
+    fn evicts_oldest_on_open() {
+        let dir = temp_dir("open-evict");
+        let blobs = [1, 2, 3, 4].map(blob);
+        {
+            let cache = open(&dir, 1000);
+            for (hash, content) in &blobs {
+                cache.insert(hash, content).unwrap();
+            }
+        }
+        // Make the first blob the newest and the last one the oldest
+        let now = SystemTime::now();
+        set_mtime(&dir.join(&blobs[0].0), now + Duration::from_secs(60));
+        for (hash, _) in &blobs[1..3] {
+            set_mtime(&dir.join(hash), now - 2 * MTIME_INTERVAL);
+        }
+        set_mtime(&dir.join(&blobs[3].0), now - 3 * MTIME_INTERVAL);
+
+        // Reading the oldest blob before restarting keeps it
+        {
+            let cache = open(&dir, 1000);
+            assert!(cache.read(&blobs[3].0, 0, 100).unwrap().is_some());
+        }
+
+        let cache = open(&dir, 200);
+        let mut expected = vec![blobs[0].0.clone(), blobs[3].0.clone()];
+        expected.sort();
+        assert_eq!(file_names(&dir), expected);
+        assert!(cache.read(&blobs[0].0, 0, 100).unwrap().is_some());
+        assert!(cache.read(&blobs[3].0, 0, 100).unwrap().is_some());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
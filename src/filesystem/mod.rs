@@ -1,3 +1,9 @@
+mod client;
+pub mod disk_cache;
 mod errors;
 mod mount;
+mod overlay;
+pub mod pool;
+pub mod registry;
 pub mod silofs;
+mod trace;
@@ -1,25 +1,88 @@
-use crate::filesystem::silofs::{ImageData, TTL};
+use crate::filesystem::disk_cache::{hash_bytes, DiskCache};
+use crate::filesystem::pool::IndexerPool;
+use crate::filesystem::silofs::{
+    BlockCache, DirEntry, ImageData, BLOCK_SIZE, STATFS_BLOCK_SIZE, TTL,
+};
+use crate::filesystem::trace::AccessTrace;
 use fuser::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, ReplyOpen,
-    Request,
+    FileAttr, FileType, Filesystem, KernelConfig, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyOpen, ReplyStatfs, ReplyXattr, Request,
+};
+use libc::{
+    c_int, EACCES, EBADF, EIO, ENODATA, ENOENT, ENOTDIR, ERANGE, EROFS, F_OK, R_OK, W_OK, X_OK,
 };
-use libc::ENOENT;
-use std::cmp::Ordering;
-use std::collections::BTreeMap;
+use std::collections::HashMap;
 use std::ffi::OsStr;
-use std::io::{self, Read, Write};
-use std::sync::{Arc, Mutex};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::atomic::Ordering as AtomicOrdering;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 use std::time::Instant;
 
+/// Longest file name, as reported by `statfs`
+const NAME_MAX: u32 = 255;
+
 /// Structure representing a mounted SiloFS instance
+#[derive(Clone)]
 pub struct SiloFSMount {
-    pub stream: Arc<Mutex<std::net::TcpStream>>,
+    pub image_name: String,
+    pub pool: Arc<IndexerPool>,
+    pub disk_cache: Arc<DiskCache>,
+    pub block_cache: BlockCache,
+    /// Check blobs fetched from the indexer against their hash
+    pub verify: bool,
     pub image_data: Arc<ImageData>,
+    pub trace: Arc<Mutex<AccessTrace>>,
+    /// Told once the kernel has initialized the session, so whoever mounted
+    /// it knows requests are being served
+    pub ready: Option<mpsc::Sender<()>>,
+    /// Listings of the open directories, by handle
+    pub dir_handles: HashMap<u64, Arc<Vec<DirEntry>>>,
+    pub next_dir_handle: u64,
 }
 
 impl SiloFSMount {
-    /// Get contents of a file by inode
-    fn get_contents(&self, ino: u64) -> io::Result<Vec<u8>> {
+    /// Warm the caches with the first block of every file in `inodes` in the
+    /// background, using up to half of the indexer connections
+    pub fn prefetch(&self, inodes: Vec<u64>) {
+        if inodes.is_empty() {
+            return;
+        }
+
+        log::info!("Prefetching {} files for {}", inodes.len(), self.image_name);
+
+        let workers = (self.pool.size() / 2).max(1).min(inodes.len());
+        let queue = Arc::new(Mutex::new(inodes.into_iter()));
+
+        for _ in 0..workers {
+            let fs = self.clone();
+            let queue = queue.clone();
+
+            thread::spawn(move || loop {
+                let ino = match queue.lock() {
+                    Ok(mut queue) => queue.next(),
+                    Err(_) => None,
+                };
+                let Some(ino) = ino else { break };
+
+                if let Err(e) = fs.read_range(ino, 0, BLOCK_SIZE as u32) {
+                    log::debug!("Failed to prefetch inode {}: {}", ino, e);
+                }
+            });
+        }
+    }
+
+    /// Record that the contents of `ino` were read during this run
+    fn record_access(&self, ino: u64) {
+        if let Ok(mut trace) = self.trace.lock() {
+            trace.record(ino);
+        }
+    }
+
+    /// Read up to `size` bytes at `offset` from a file by inode, fetching
+    /// only the blocks, or chunks for chunked files, that cover the range
+    fn read_range(&self, ino: u64, offset: u64, size: u32) -> io::Result<Vec<u8>> {
         let hash = self.image_data.inode_to_hash.get(&ino).ok_or_else(|| {
             io::Error::new(
                 io::ErrorKind::NotFound,
@@ -27,40 +90,172 @@ impl SiloFSMount {
             )
         })?;
 
-        if let Some(content) = self.image_data.content_cache.get(hash) {
-            return Ok(content.to_vec());
+        let file_size = self.get_attr(ino)?.size;
+        if size == 0 || offset >= file_size {
+            return Ok(Vec::new());
         }
 
-        let mut stream = self.stream.lock().map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to lock stream: {}", e),
-            )
-        })?;
+        let end = (offset + size as u64).min(file_size);
 
-        stream.write_all(hash.as_bytes()).map_err(|e| {
-            io::Error::new(io::ErrorKind::Other, format!("Failed to write hash: {}", e))
-        })?;
+        let chunks = match self.image_data.chunks.get(hash) {
+            Some(chunks) => chunks,
+            None => return self.read_blob(hash, file_size, offset, end),
+        };
 
-        let mut size_buf = [0u8; 8];
-        stream.read_exact(&mut size_buf).map_err(|e| {
-            io::Error::new(io::ErrorKind::Other, format!("Failed to read size: {}", e))
-        })?;
-        let size = u64::from_be_bytes(size_buf);
+        let mut data = Vec::with_capacity((end - offset) as usize);
+        let first = chunks.partition_point(|chunk| chunk.offset + chunk.length <= offset);
+        for chunk in &chunks[first..] {
+            if chunk.offset >= end {
+                break;
+            }
 
-        let mut content = vec![0u8; size as usize];
-        stream.read_exact(&mut content).map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to read content: {}", e),
-            )
-        })?;
+            let from = offset.max(chunk.offset) - chunk.offset;
+            let to = end.min(chunk.offset + chunk.length) - chunk.offset;
+            data.extend(self.read_blob(&chunk.hash, chunk.length, from, to)?);
+        }
+
+        Ok(data)
+    }
+
+    /// Read bytes `offset..end` of a blob of `blob_size` bytes through the
+    /// block cache
+    fn read_blob(&self, hash: &str, blob_size: u64, offset: u64, end: u64) -> io::Result<Vec<u8>> {
+        let first_block = offset / BLOCK_SIZE;
+        let last_block = (end - 1) / BLOCK_SIZE;
+
+        let blocks = self.get_blocks(hash, blob_size, first_block, last_block)?;
+
+        let mut data = Vec::with_capacity((end - offset) as usize);
+        for (block, content) in (first_block..=last_block).zip(blocks) {
+            let block_start = block * BLOCK_SIZE;
+            let from = (offset.max(block_start) - block_start) as usize;
+            let to = ((end - block_start) as usize).min(content.len());
+            data.extend_from_slice(&content[from.min(to)..to]);
+        }
+
+        Ok(data)
+    }
+
+    /// Get blocks `first..=last` of a blob from the shared block cache,
+    /// fetching the missing ones in a single range
+    fn get_blocks(
+        &self,
+        hash: &str,
+        file_size: u64,
+        first: u64,
+        last: u64,
+    ) -> io::Result<Vec<Arc<Vec<u8>>>> {
+        let mut blocks: Vec<Option<Arc<Vec<u8>>>> = (first..=last)
+            .map(|block| self.block_cache.get(&(hash.to_string(), block)))
+            .collect();
+
+        let missed = blocks.iter().filter(|block| block.is_none()).count() as u64;
+        let stats = &self.image_data.cache_stats;
+        stats
+            .hits
+            .fetch_add(blocks.len() as u64 - missed, AtomicOrdering::Relaxed);
+        stats.misses.fetch_add(missed, AtomicOrdering::Relaxed);
+
+        let missing = (
+            blocks.iter().position(Option::is_none),
+            blocks.iter().rposition(Option::is_none),
+        );
+
+        if let (Some(lo), Some(hi)) = missing {
+            let start = first + lo as u64;
+            let count = (hi - lo + 1) as u64;
+
+            let data = self.fetch_range(
+                hash,
+                file_size,
+                start * BLOCK_SIZE,
+                (count * BLOCK_SIZE) as usize,
+            )?;
+
+            for (i, chunk) in data.chunks(BLOCK_SIZE as usize).enumerate() {
+                let chunk = Arc::new(chunk.to_vec());
+                self.block_cache
+                    .insert((hash.to_string(), start + i as u64), chunk.clone());
+                blocks[lo + i] = Some(chunk);
+            }
+        }
+
+        blocks
+            .into_iter()
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("Indexer returned a short range for {}", hash),
+                )
+            })
+    }
+
+    /// Fetch a range of a blob that is not in memory, from the disk cache if
+    /// it holds the blob. Blobs small enough to be fetched whole are
+    /// verified and persisted on a miss; larger ones are read from the
+    /// indexer by range and not verified, as that would take downloading
+    /// them whole. Files indexed with chunking are verified chunk by chunk.
+    fn fetch_range(
+        &self,
+        hash: &str,
+        file_size: u64,
+        offset: u64,
+        length: usize,
+    ) -> io::Result<Vec<u8>> {
+        if let Some(data) = self.disk_cache.read(hash, offset, length)? {
+            return Ok(data);
+        }
+
+        if file_size > self.disk_cache.max_blob_size() {
+            return self.pool.read_range(hash, offset, length as u32);
+        }
+
+        let content = self.pool.read_range(hash, 0, file_size as u32)?;
+        if self.verify {
+            self.check_integrity(hash, &hash_bytes(&content))?;
+        }
+        if let Err(e) = self.disk_cache.insert(hash, &content) {
+            log::warn!("Failed to persist {} to disk cache: {}", hash, e);
+        }
+
+        let start = (offset as usize).min(content.len());
+        let end = (start + length).min(content.len());
+        Ok(content[start..end].to_vec())
+    }
+
+    /// Compare the hash of a blob received from the indexer with the one it
+    /// was requested by, counting and logging mismatches
+    fn check_integrity(&self, hash: &str, actual: &str) -> io::Result<()> {
+        if actual == hash {
+            return Ok(());
+        }
 
         self.image_data
-            .content_cache
-            .insert(hash.to_string(), content.clone());
+            .integrity_failures
+            .fetch_add(1, AtomicOrdering::Relaxed);
+        log::error!(
+            "Integrity failure in {}: blob {} received from the indexer hashes to {}",
+            self.image_name,
+            hash,
+            actual
+        );
 
-        Ok(content)
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Blob {} failed integrity verification", hash),
+        ))
+    }
+
+    /// Error returned to the container for a failed read of `ino`. A file
+    /// is only missing if its inode is not part of the image: the indexer
+    /// failing to serve its contents, whatever the reason, is an I/O error.
+    fn read_errno(&self, ino: u64) -> c_int {
+        if self.image_data.file_attr_cache.contains_key(&ino) {
+            EIO
+        } else {
+            ENOENT
+        }
     }
 
     /// Get file attributes by inode
@@ -74,41 +269,74 @@ impl SiloFSMount {
             })
     }
 
-    /// Get children of a directory by inode
-    fn get_children(&self, ino: u64) -> io::Result<std::collections::HashMap<String, u64>> {
-        self.image_data
-            .directory_cache
-            .get(&ino)
-            .cloned()
-            .ok_or_else(|| {
-                io::Error::new(
-                    io::ErrorKind::NotFound,
-                    format!("Directory inode {} not found", ino),
-                )
-            })
+    /// Inode of the entry `name` of a directory, including `.` and `..`
+    fn lookup_child(&self, parent: u64, name: &OsStr) -> io::Result<Option<u64>> {
+        if !self.image_data.listings.contains_key(&parent) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Directory inode {} not found", parent),
+            ));
+        }
+
+        let child = match name.as_bytes() {
+            b"." => Some(parent),
+            b".." => self.image_data.parents.get(&parent).copied(),
+            // Empty directories have no entries recorded
+            _ => self
+                .image_data
+                .directory_cache
+                .get(&parent)
+                .and_then(|children| children.get(name))
+                .copied(),
+        };
+
+        Ok(child)
     }
 }
 
 impl Filesystem for SiloFSMount {
+    fn init(&mut self, _req: &Request, _config: &mut KernelConfig) -> Result<(), c_int> {
+        if let Some(ready) = self.ready.take() {
+            // Nobody is waiting anymore if the mount timed out
+            let _ = ready.send(());
+        }
+        Ok(())
+    }
+
+    fn destroy(&mut self) {
+        let inodes = match self.trace.lock() {
+            Ok(trace) => trace.inodes().to_vec(),
+            Err(_) => return,
+        };
+
+        if inodes.is_empty() {
+            return;
+        }
+
+        match self.pool.put_trace(&self.image_name, &inodes) {
+            Ok(()) => log::info!(
+                "Saved access trace of {} files for {}",
+                inodes.len(),
+                self.image_name
+            ),
+            Err(e) => log::warn!("Failed to save access trace for {}: {}", self.image_name, e),
+        }
+    }
+
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         let start = Instant::now();
 
-        match self.get_children(parent) {
-            Ok(children) => {
-                if let Some(&child_ino) = children.get(name.to_str().unwrap_or("")) {
-                    match self.get_attr(child_ino) {
-                        Ok(attr) => {
-                            reply.entry(&TTL, &attr, 0);
-                        }
-                        Err(e) => {
-                            log::error!("Failed to get attributes for inode {}: {}", child_ino, e);
-                            reply.error(ENOENT);
-                        }
-                    }
-                } else {
+        match self.lookup_child(parent, name) {
+            Ok(Some(child_ino)) => match self.get_attr(child_ino) {
+                Ok(attr) => {
+                    reply.entry(&TTL, &attr, 0);
+                }
+                Err(e) => {
+                    log::error!("Failed to get attributes for inode {}: {}", child_ino, e);
                     reply.error(ENOENT);
                 }
-            }
+            },
+            Ok(None) => reply.error(ENOENT),
             Err(e) => {
                 log::error!("Failed to get children for parent inode {}: {}", parent, e);
                 reply.error(ENOENT);
@@ -148,16 +376,13 @@ impl Filesystem for SiloFSMount {
         reply: ReplyData,
     ) {
         let start = Instant::now();
+        self.record_access(ino);
 
-        match self.get_contents(ino) {
-            Ok(content) => {
-                let start = offset as usize;
-                let end = (offset as usize + size as usize).min(content.len());
-                reply.data(&content[start..end]);
-            }
+        match self.read_range(ino, offset as u64, size) {
+            Ok(data) => reply.data(&data),
             Err(e) => {
                 log::error!("Failed to read contents for inode {}: {}", ino, e);
-                reply.error(ENOENT);
+                reply.error(self.read_errno(ino));
             }
         }
 
@@ -189,61 +414,124 @@ impl Filesystem for SiloFSMount {
         }
     }
 
+    fn opendir(&mut self, _req: &Request, ino: u64, _flags: i32, reply: ReplyOpen) {
+        match self.image_data.listings.get(&ino) {
+            Some(listing) => {
+                let fh = self.next_dir_handle;
+                self.next_dir_handle += 1;
+                self.dir_handles.insert(fh, listing.clone());
+                reply.opened(fh, 0);
+            }
+            None if self.get_attr(ino).is_ok() => reply.error(ENOTDIR),
+            None => {
+                log::error!("Failed to open directory with inode {}: not found", ino);
+                reply.error(ENOENT);
+            }
+        }
+    }
+
     fn readdir(
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
-        match self.get_children(ino) {
-            Ok(children) => {
-                let mut entries = BTreeMap::new();
+        let Some(entries) = self.dir_handles.get(&fh) else {
+            log::error!("Directory handle {} of inode {} is not open", fh, ino);
+            reply.error(EBADF);
+            return;
+        };
 
-                entries.insert(".".to_string(), (ino, FileType::Directory));
-                entries.insert("..".to_string(), (ino, FileType::Directory));
+        // The offset is the cookie of the last entry returned, its position
+        // in the listing plus one, so each call starts right after it
+        let start = (offset.max(0) as usize).min(entries.len());
+        for (i, entry) in entries[start..].iter().enumerate() {
+            if reply.add(entry.ino, (start + i + 1) as i64, entry.kind, &entry.name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
 
-                for (name, &child_ino) in children.iter() {
-                    if let Ok(attr) = self.get_attr(child_ino) {
-                        entries.insert(name.clone(), (child_ino, attr.kind));
-                    }
-                }
+    fn releasedir(&mut self, _req: &Request, _ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
+        self.dir_handles.remove(&fh);
+        reply.ok();
+    }
 
-                let mut sorted_entries: Vec<_> = entries.into_iter().collect();
-                sorted_entries.sort_unstable_by(|(name_a, (_, type_a)), (name_b, (_, type_b))| {
-                    match (type_a, type_b) {
-                        (FileType::Directory, FileType::Directory) => name_a.cmp(name_b),
-                        (FileType::Directory, _) => Ordering::Less,
-                        (_, FileType::Directory) => Ordering::Greater,
-                        _ => name_a.cmp(name_b),
-                    }
-                });
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        // Nothing can be written to the image, so nothing is free
+        reply.statfs(
+            self.image_data.total_blocks,
+            0,
+            0,
+            self.image_data.file_attr_cache.len() as u64,
+            0,
+            STATFS_BLOCK_SIZE as u32,
+            NAME_MAX,
+            STATFS_BLOCK_SIZE as u32,
+        );
+    }
 
-                for (i, (name, (child_ino, file_type))) in
-                    sorted_entries.into_iter().enumerate().skip(offset as usize)
-                {
-                    if reply.add(child_ino, (i + 1) as i64, file_type, name) {
-                        break;
-                    }
-                }
-                reply.ok();
-            }
-            Err(e) => {
-                log::error!("Failed to read directory contents for inode {}: {}", ino, e);
+    fn access(&mut self, req: &Request, ino: u64, mask: i32, reply: ReplyEmpty) {
+        let attr = match self.get_attr(ino) {
+            Ok(attr) => attr,
+            Err(_) => {
                 reply.error(ENOENT);
+                return;
             }
+        };
+
+        if mask & W_OK != 0 {
+            reply.error(EROFS);
+        } else if mask == F_OK || access_allowed(&attr, req.uid(), req.gid(), mask) {
+            reply.ok();
+        } else {
+            reply.error(EACCES);
         }
     }
 
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let value = name.to_str().and_then(|name| {
+            self.image_data
+                .xattrs
+                .get(&ino)
+                .and_then(|xattrs| xattrs.get(name))
+        });
+
+        match value {
+            Some(value) => reply_xattr(value, size, reply),
+            None => reply.error(ENODATA),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        // Names, each followed by a NUL byte
+        let mut names = Vec::new();
+        if let Some(xattrs) = self.image_data.xattrs.get(&ino) {
+            for name in xattrs.keys() {
+                names.extend_from_slice(name.as_bytes());
+                names.push(0);
+            }
+        }
+
+        reply_xattr(&names, size, reply);
+    }
+
     fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
         let start = Instant::now();
+        self.record_access(ino);
+
+        let target = self
+            .get_attr(ino)
+            .and_then(|attr| self.read_range(ino, 0, attr.size as u32));
 
-        match self.get_contents(ino) {
+        match target {
             Ok(content) => reply.data(&content),
             Err(e) => {
                 log::error!("Failed to read symlink for inode {}: {}", ino, e);
-                reply.error(ENOENT);
+                reply.error(self.read_errno(ino));
             }
         }
 
@@ -256,3 +544,208 @@ impl Filesystem for SiloFSMount {
         }
     }
 }
+
+/// Whether a user may read or execute a file as asked by the `R_OK` and
+/// `X_OK` bits of `mask`, from the mode bits of its owner, group or others.
+/// Only the primary group of the user is known.
+fn access_allowed(attr: &FileAttr, uid: u32, gid: u32, mask: i32) -> bool {
+    let mask = (mask & (R_OK | X_OK)) as u16;
+
+    // Root reads anything, and executes what anyone can execute
+    if uid == 0 {
+        return mask & X_OK as u16 == 0
+            || attr.kind == FileType::Directory
+            || attr.perm & 0o111 != 0;
+    }
+
+    let granted = if uid == attr.uid {
+        attr.perm >> 6
+    } else if gid == attr.gid {
+        attr.perm >> 3
+    } else {
+        attr.perm
+    };
+    granted & mask == mask
+}
+
+/// Answer an xattr request with the size of `data` when `size` is 0, as the
+/// caller is probing for the buffer to allocate, and with `data` otherwise
+fn reply_xattr(data: &[u8], size: u32, reply: ReplyXattr) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::disk_cache::DiskCacheConfig;
+    use crate::filesystem::pool::PoolConfig;
+    use crate::filesystem::silofs::CacheStats;
+    use crate::protocol::{parse_range_request, Frame, Opcode};
+    use fuser::FUSE_ROOT_ID;
+    use std::net::TcpListener;
+    use std::sync::atomic::AtomicU64;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    const LARGE_HASH: &str = "1111111111111111111111111111111111111111111111111111111111111111";
+    const SMALL_HASH: &str = "2222222222222222222222222222222222222222222222222222222222222222";
+    const LARGE_SIZE: u64 = 64 * 1024 * 1024;
+    const SMALL_SIZE: u64 = 1000;
+
+    /// Byte at `position` of every blob served by `serve_blobs`
+    fn blob_byte(position: u64) -> u8 {
+        (position % 251) as u8
+    }
+
+    /// Offset and length of every range requested from `serve_blobs`
+    type Requests = Arc<Mutex<Vec<(u64, u32)>>>;
+
+    /// Serve the blobs of `LARGE_HASH` and `SMALL_HASH`, neither of which
+    /// matches its hash, recording every range requested
+    fn serve_blobs() -> (String, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let recorded = recorded.clone();
+                thread::spawn(move || {
+                    while let Ok(frame) = Frame::read_from(&mut stream) {
+                        let reply = match frame.opcode {
+                            Opcode::Hello => Frame::hello(frame.request_id, 0),
+                            Opcode::ReadRange => {
+                                let (hash, offset, length) =
+                                    parse_range_request(&frame.payload).unwrap();
+                                recorded.lock().unwrap().push((offset, length));
+                                let size = match hash {
+                                    h if h == LARGE_HASH.as_bytes() => LARGE_SIZE,
+                                    _ => SMALL_SIZE,
+                                };
+                                let end = (offset + length as u64).min(size);
+                                let data = (offset..end).map(blob_byte).collect();
+                                Frame::new(Opcode::Range, frame.request_id, data)
+                            }
+                            _ => break,
+                        };
+                        reply.write_to(&mut stream).unwrap();
+                    }
+                });
+            }
+        });
+
+        (addr, requests)
+    }
+
+    fn file(ino: u64, size: u64) -> FileAttr {
+        FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FileType::RegularFile,
+            perm: 0o644,
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            flags: 0,
+            blksize: 4096,
+        }
+    }
+
+    fn mount(addr: String, cache_dir: &str) -> SiloFSMount {
+        let cache_dir =
+            std::env::temp_dir().join(format!("silo-mount-{}-{}", std::process::id(), cache_dir));
+        let _ = std::fs::remove_dir_all(&cache_dir);
+
+        let pool_config = PoolConfig {
+            size: 1,
+            request_timeout: Duration::from_secs(5),
+            max_retries: 0,
+            compression: false,
+            ..PoolConfig::default()
+        };
+        let disk_cache = DiskCache::open(DiskCacheConfig {
+            path: cache_dir,
+            max_bytes: 64 * 1024 * 1024,
+            max_blob_size: 8 * 1024 * 1024,
+        })
+        .unwrap();
+
+        let image_data = ImageData {
+            directory_cache: HashMap::new(),
+            parents: HashMap::from([(FUSE_ROOT_ID, FUSE_ROOT_ID)]),
+            listings: HashMap::new(),
+            file_attr_cache: HashMap::from([(2, file(2, LARGE_SIZE)), (3, file(3, SMALL_SIZE))]),
+            inode_to_hash: HashMap::from([
+                (2, LARGE_HASH.to_string()),
+                (3, SMALL_HASH.to_string()),
+            ]),
+            chunks: HashMap::new(),
+            xattrs: HashMap::new(),
+            total_blocks: 0,
+            cache_stats: CacheStats::default(),
+            integrity_failures: AtomicU64::new(0),
+        };
+
+        SiloFSMount {
+            image_name: String::from("test:latest"),
+            pool: Arc::new(IndexerPool::new(vec![addr], pool_config).unwrap()),
+            disk_cache: Arc::new(disk_cache),
+            block_cache: BlockCache::new(1024),
+            verify: true,
+            image_data: Arc::new(image_data),
+            trace: Arc::new(Mutex::new(AccessTrace::default())),
+            ready: None,
+            dir_handles: HashMap::new(),
+            next_dir_handle: 1,
+        }
+    }
+
+    #[test]
+    fn large_blob_read_stays_ranged_when_verifying() {
+        let (addr, requests) = serve_blobs();
+        let fs = mount(addr, "large");
+
+        let data = fs.read_range(2, 5 * BLOCK_SIZE + 10, 100).unwrap();
+        assert_eq!(
+            data,
+            (5 * BLOCK_SIZE + 10..5 * BLOCK_SIZE + 110)
+                .map(blob_byte)
+                .collect::<Vec<_>>()
+        );
+
+        // Only the block holding the range, not the whole blob
+        assert_eq!(
+            *requests.lock().unwrap(),
+            [(5 * BLOCK_SIZE, BLOCK_SIZE as u32)]
+        );
+    }
+
+    #[test]
+    fn small_blob_is_verified() {
+        let (addr, requests) = serve_blobs();
+        let fs = mount(addr, "small");
+
+        let err = fs.read_range(3, 0, 100).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            fs.image_data
+                .integrity_failures
+                .load(AtomicOrdering::Relaxed),
+            1
+        );
+        assert_eq!(*requests.lock().unwrap(), [(0, SMALL_SIZE as u32)]);
+    }
+}
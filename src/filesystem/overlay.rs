@@ -0,0 +1,102 @@
+use nix::errno::Errno;
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Writable layer of a container: an overlayfs mount merging a scratch
+/// directory over a read-only lower directory, usually a SiloFS mount.
+/// Everything the container writes lands in the scratch directory, and the
+/// lower directory is never modified.
+pub struct Overlay {
+    merged: PathBuf,
+    scratch: PathBuf,
+}
+
+impl Overlay {
+    /// Mount `lower` with a writable layer at `merged`, keeping the written
+    /// files under `scratch`
+    pub fn mount(lower: &Path, scratch: &Path, merged: &Path) -> io::Result<Self> {
+        let upper = scratch.join("upper");
+        let work = scratch.join("work");
+        for dir in [&upper, &work, merged] {
+            fs::create_dir_all(dir)?;
+        }
+
+        let options = format!(
+            "lowerdir={},upperdir={},workdir={}",
+            option_path(lower)?,
+            option_path(&upper)?,
+            option_path(&work)?
+        );
+
+        mount(
+            Some("overlay"),
+            merged,
+            Some("overlay"),
+            MsFlags::empty(),
+            Some(options.as_str()),
+        )
+        .map_err(|e| {
+            io::Error::new(
+                io::Error::from(e).kind(),
+                format!("Failed to mount overlay at {}: {}", merged.display(), e),
+            )
+        })?;
+
+        Ok(Self {
+            merged: merged.to_path_buf(),
+            scratch: scratch.to_path_buf(),
+        })
+    }
+
+    /// Root of the writable tree
+    pub fn merged(&self) -> &Path {
+        &self.merged
+    }
+
+    /// Files written through the overlay, with deleted files recorded as
+    /// overlayfs whiteouts
+    pub fn upper(&self) -> PathBuf {
+        self.scratch.join("upper")
+    }
+
+    /// Unmount the overlay and delete the scratch directory, or with `keep`
+    /// leave the written files in it
+    pub fn unmount(self, keep: bool) -> io::Result<()> {
+        match umount2(&self.merged, MntFlags::empty()) {
+            // Something still has files open in it, detach it so it goes
+            // away once they are closed
+            Err(Errno::EBUSY) => umount2(&self.merged, MntFlags::MNT_DETACH)?,
+            result => result?,
+        }
+        fs::remove_dir(&self.merged)?;
+
+        if keep {
+            // The work directory is only of use to a mounted overlay
+            fs::remove_dir_all(self.scratch.join("work"))?;
+            log::info!(
+                "Kept files written to the overlay in {}",
+                self.upper().display()
+            );
+        } else {
+            fs::remove_dir_all(&self.scratch)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Absolute path of a directory as it can go in the overlayfs mount options,
+/// which are comma separated and list lower directories separated by colons
+fn option_path(dir: &Path) -> io::Result<String> {
+    let dir = fs::canonicalize(dir)?;
+
+    match dir.to_str() {
+        Some(dir) if !dir.contains([',', ':']) => Ok(dir.to_string()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} cannot be used in an overlay", dir.display()),
+        )),
+    }
+}
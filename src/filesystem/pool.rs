@@ -0,0 +1,275 @@
+use crate::filesystem::client::IndexerClient;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Upper bound on the delay between two retries
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Settings for the pool of connections SiloFS keeps to the indexer
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Maximum number of connections open at once
+    pub size: usize,
+    /// Maximum time a single request may wait on the indexer, also used when
+    /// connecting and when waiting for a free connection
+    pub request_timeout: Duration,
+    /// Number of times a request is retried after the connection drops
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled after every failed attempt
+    pub retry_backoff: Duration,
+    /// Time after which a failing request is no longer retried, counted from
+    /// its first attempt
+    pub retry_deadline: Duration,
+    /// Whether to ask the indexer to compress the data it sends
+    pub compression: bool,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            size: 8,
+            request_timeout: Duration::from_secs(30),
+            max_retries: 5,
+            retry_backoff: Duration::from_millis(100),
+            retry_deadline: Duration::from_secs(60),
+            compression: true,
+        }
+    }
+}
+
+struct PoolState {
+    idle: Vec<IndexerClient>,
+    open: usize,
+}
+
+/// Pool of indexer connections shared by every mount, so that requests from
+/// different mounts and images can be in flight at the same time.
+///
+/// Connections are opened lazily against the first reachable address in
+/// `tcp_addrs`, starting from the one that last worked. Requests that fail
+/// because the connection dropped are retried with exponential backoff until
+/// `retry_deadline`. This is safe since every request to the indexer is a
+/// read, except `PutTrace` which replaces the stored trace and can be sent
+/// again as is.
+pub struct IndexerPool {
+    tcp_addrs: Vec<String>,
+    preferred: AtomicUsize,
+    config: PoolConfig,
+    state: Mutex<PoolState>,
+    available: Condvar,
+}
+
+impl IndexerPool {
+    /// Create a pool, opening a first connection to check an indexer is reachable
+    pub fn new(tcp_addrs: Vec<String>, config: PoolConfig) -> io::Result<Self> {
+        if config.size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Indexer pool size must be at least 1",
+            ));
+        }
+
+        if tcp_addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "At least one indexer address is required",
+            ));
+        }
+
+        let pool = Self {
+            tcp_addrs,
+            preferred: AtomicUsize::new(0),
+            config,
+            state: Mutex::new(PoolState {
+                idle: Vec::new(),
+                open: 1,
+            }),
+            available: Condvar::new(),
+        };
+
+        let client = pool.connect()?;
+        pool.lock_state()?.idle.push(client);
+
+        Ok(pool)
+    }
+
+    /// Fetch the serialized metadata of an image
+    pub fn get_image_data(&self, image_name: &str) -> io::Result<Vec<u8>> {
+        self.with_client(|client| client.get_image_data(image_name))
+    }
+
+    /// Fetch up to `length` bytes of a blob starting at `offset`
+    pub fn read_range(&self, hash: &str, offset: u64, length: u32) -> io::Result<Vec<u8>> {
+        self.with_client(|client| client.read_range(hash, offset, length))
+    }
+
+    /// Store the access trace recorded for an image
+    pub fn put_trace(&self, image_name: &str, inodes: &[u64]) -> io::Result<()> {
+        self.with_client(|client| client.put_trace(image_name, inodes))
+    }
+
+    /// Fetch the access trace recorded for an image
+    pub fn get_trace(&self, image_name: &str) -> io::Result<Vec<u64>> {
+        self.with_client(|client| client.get_trace(image_name))
+    }
+
+    /// Maximum number of connections the pool opens at once
+    pub fn size(&self) -> usize {
+        self.config.size
+    }
+
+    /// Run a request on a pooled connection, retrying it with backoff while
+    /// the indexer is unreachable
+    fn with_client<T>(&self, f: impl Fn(&mut IndexerClient) -> io::Result<T>) -> io::Result<T> {
+        let deadline = Instant::now() + self.config.retry_deadline;
+        let mut delay = self.config.retry_backoff;
+        let mut attempt = 0;
+
+        loop {
+            let result = self.checkout().and_then(|mut client| {
+                let result = f(&mut client);
+                self.checkin(client, result.as_ref().err())?;
+                result
+            });
+
+            match result {
+                Err(e)
+                    if is_connection_error(&e)
+                        && attempt < self.config.max_retries
+                        && Instant::now() + delay < deadline =>
+                {
+                    log::warn!(
+                        "Indexer request failed ({}), retrying in {:?} ({}/{})",
+                        e,
+                        delay,
+                        attempt + 1,
+                        self.config.max_retries
+                    );
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(MAX_RETRY_BACKOFF);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Return a connection after a request. Connections whose stream failed
+    /// are closed, along with every idle one since they most likely point at
+    /// the same dead indexer.
+    fn checkin(&self, client: IndexerClient, error: Option<&io::Error>) -> io::Result<()> {
+        let mut state = self.lock_state()?;
+
+        match error {
+            Some(e) if is_connection_error(e) => {
+                state.open -= 1 + state.idle.len();
+                state.idle.clear();
+            }
+            // A malformed response may leave the stream in the middle of a
+            // frame, but says nothing about the other connections
+            Some(e) if e.kind() == io::ErrorKind::InvalidData => state.open -= 1,
+            _ => state.idle.push(client),
+        }
+        self.available.notify_all();
+
+        Ok(())
+    }
+
+    /// Take an idle connection, open a new one if under the limit, or wait
+    /// for one to be returned
+    fn checkout(&self) -> io::Result<IndexerClient> {
+        let deadline = Instant::now() + self.config.request_timeout;
+        let mut state = self.lock_state()?;
+
+        loop {
+            if let Some(client) = state.idle.pop() {
+                return Ok(client);
+            }
+
+            if state.open < self.config.size {
+                state.open += 1;
+                drop(state);
+
+                return self.connect().inspect_err(|_| {
+                    if let Ok(mut state) = self.state.lock() {
+                        state.open -= 1;
+                    }
+                    self.available.notify_one();
+                });
+            }
+
+            let remaining = deadline
+                .checked_duration_since(Instant::now())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "Timed out waiting for an indexer connection",
+                    )
+                })?;
+
+            state = self
+                .available
+                .wait_timeout(state, remaining)
+                .map_err(|e| io::Error::other(format!("Failed to lock pool: {}", e)))?
+                .0;
+        }
+    }
+
+    /// Connect to the first reachable indexer, starting from the one that
+    /// last worked and failing over to the others in order
+    fn connect(&self) -> io::Result<IndexerClient> {
+        let preferred = self.preferred.load(Ordering::Relaxed);
+        let mut last_error = None;
+
+        for i in 0..self.tcp_addrs.len() {
+            let index = (preferred + i) % self.tcp_addrs.len();
+            let tcp_addr = &self.tcp_addrs[index];
+
+            match IndexerClient::connect(
+                tcp_addr,
+                self.config.request_timeout,
+                self.config.compression,
+            ) {
+                Ok(client) => {
+                    if index != preferred {
+                        log::warn!("Failing over to indexer at {}", tcp_addr);
+                        self.preferred.store(index, Ordering::Relaxed);
+                    }
+                    return Ok(client);
+                }
+                Err(e) => {
+                    log::warn!("Failed to connect to indexer at {}: {}", tcp_addr, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error.unwrap())
+    }
+
+    fn lock_state(&self) -> io::Result<std::sync::MutexGuard<'_, PoolState>> {
+        self.state
+            .lock()
+            .map_err(|e| io::Error::other(format!("Failed to lock pool: {}", e)))
+    }
+}
+
+/// Whether an error means the connection itself is unusable, as opposed to
+/// an error frame answered by the indexer or a malformed response, which
+/// sending the request again would not fix
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::WouldBlock
+    )
+}
@@ -0,0 +1,342 @@
+use crate::filesystem::overlay::Overlay;
+use crate::filesystem::silofs::SiloFS;
+use dashmap::DashMap;
+use fuser::BackgroundSession;
+use nix::errno::Errno;
+use nix::mount::{umount2, MntFlags};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Prefix of the names given to containers, see `container_name`
+const CONTAINER_PREFIX: &str = "container-";
+
+/// Mounts of a running container
+struct ActiveMount {
+    image_name: String,
+    mounted_at: SystemTime,
+    overlay: Overlay,
+    session: BackgroundSession,
+}
+
+/// Description of a running container's mounts, for inspection
+#[derive(Serialize)]
+pub struct MountInfo {
+    pub container: String,
+    pub image_name: String,
+    /// Root of the container's writable tree
+    pub rootfs: PathBuf,
+    /// Image as mounted by SiloFS
+    pub lower: PathBuf,
+    /// Files the container has written
+    pub upper: PathBuf,
+    /// Seconds since the Unix epoch
+    pub mounted_at: u64,
+}
+
+/// Tracks the mounts of every running container, so they are unmounted and
+/// their directories deleted when the container exits. Each container gets a
+/// directory in `mount_dir` holding the SiloFS mount of its image (`lower`)
+/// and the writable overlay on top of it (`rootfs`), and a scratch directory
+/// in `scratch_dir` holding the overlay's upper layer.
+pub struct MountRegistry {
+    mount_dir: PathBuf,
+    scratch_dir: PathBuf,
+    /// Keep the files a container wrote after it exits
+    keep_writes: bool,
+    mounts: DashMap<String, ActiveMount>,
+}
+
+/// Unmounts a container's mounts when dropped, whichever way the container
+/// ends
+pub struct ContainerMount {
+    registry: Arc<MountRegistry>,
+    container: String,
+    rootfs: PathBuf,
+}
+
+impl ContainerMount {
+    /// Root of the container's writable tree
+    pub fn rootfs(&self) -> &Path {
+        &self.rootfs
+    }
+}
+
+impl Drop for ContainerMount {
+    fn drop(&mut self) {
+        if let Err(e) = self.registry.unmount(&self.container) {
+            log::warn!("Failed to unmount {}: {}", self.container, e);
+        }
+    }
+}
+
+impl MountRegistry {
+    /// Create a registry, cleaning up the mounts and directories left behind
+    /// by containers of a previous run that did not exit cleanly
+    pub fn new(mount_dir: PathBuf, scratch_dir: PathBuf, keep_writes: bool) -> io::Result<Self> {
+        fs::create_dir_all(&mount_dir)?;
+        fs::create_dir_all(&scratch_dir)?;
+
+        let registry = Self {
+            mount_dir,
+            scratch_dir,
+            keep_writes,
+            mounts: DashMap::new(),
+        };
+
+        let swept = registry.sweep()?;
+        if swept > 0 {
+            log::info!("Cleaned up {} stale container mounts", swept);
+        }
+
+        Ok(registry)
+    }
+
+    /// Mount an image for a container, with a writable overlay on top of it
+    pub fn mount(
+        self: &Arc<Self>,
+        container: &str,
+        filesystem: &SiloFS,
+        image_name: &str,
+        verify: bool,
+    ) -> io::Result<ContainerMount> {
+        if !is_container_name(container) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a container name", container),
+            ));
+        }
+
+        if self.mounts.contains_key(container) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Container {} is already mounted", container),
+            ));
+        }
+
+        let dir = self.mount_dir.join(container);
+        let lower = dir.join("lower");
+        let rootfs = dir.join("rootfs");
+        let scratch = self.scratch_dir.join(container);
+        fs::create_dir_all(&lower)?;
+
+        let result = filesystem
+            .mount(image_name, &lower, verify)
+            .and_then(|session| {
+                // The image is shared by every container, so writes go to a
+                // layer of their own
+                let overlay = Overlay::mount(&lower, &scratch, &rootfs)?;
+                Ok((session, overlay))
+            });
+
+        let (session, overlay) = match result {
+            Ok(mounted) => mounted,
+            Err(e) => {
+                if let Err(e) = self.sweep_container(container) {
+                    log::warn!("Failed to clean up {}: {}", container, e);
+                }
+                return Err(e);
+            }
+        };
+
+        self.mounts.insert(
+            container.to_string(),
+            ActiveMount {
+                image_name: image_name.to_string(),
+                mounted_at: SystemTime::now(),
+                overlay,
+                session,
+            },
+        );
+
+        Ok(ContainerMount {
+            registry: self.clone(),
+            container: container.to_string(),
+            rootfs,
+        })
+    }
+
+    /// Unmount the overlay and image of a container and delete their
+    /// directories, keeping the files it wrote if the registry is set to
+    pub fn unmount(&self, container: &str) -> io::Result<()> {
+        let Some((_, mount)) = self.mounts.remove(container) else {
+            return Ok(());
+        };
+
+        mount.overlay.unmount(self.keep_writes)?;
+
+        // Dropping the session unmounts the image, but leaves it to
+        // fusermount when auto unmounting, which may not be done yet
+        drop(mount.session);
+        let dir = self.mount_dir.join(container);
+        detach(&dir.join("lower"))?;
+        fs::remove_dir(dir.join("lower"))?;
+        fs::remove_dir(&dir)?;
+
+        log::info!("Unmounted {}", container);
+        Ok(())
+    }
+
+    /// Mounts of the running containers, sorted by container
+    pub fn active(&self) -> Vec<MountInfo> {
+        let mut mounts: Vec<_> = self
+            .mounts
+            .iter()
+            .map(|entry| {
+                let dir = self.mount_dir.join(entry.key());
+                MountInfo {
+                    container: entry.key().clone(),
+                    image_name: entry.image_name.clone(),
+                    rootfs: entry.overlay.merged().to_path_buf(),
+                    lower: dir.join("lower"),
+                    upper: entry.overlay.upper(),
+                    mounted_at: entry
+                        .mounted_at
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |since| since.as_secs()),
+                }
+            })
+            .collect();
+
+        mounts.sort_by(|a, b| a.container.cmp(&b.container));
+        mounts
+    }
+
+    /// Clean up the directories of the containers of a previous run,
+    /// returning how many were cleaned up. Scratch directories are left alone
+    /// when writes are kept. Only directories named like containers are
+    /// touched, whatever else the mount and scratch directories hold.
+    fn sweep(&self) -> io::Result<usize> {
+        let mut dirs = vec![&self.mount_dir];
+        if !self.keep_writes {
+            dirs.push(&self.scratch_dir);
+        }
+
+        let mut containers = BTreeSet::new();
+        for dir in dirs {
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                match entry.file_name().into_string() {
+                    Ok(name) if is_container_name(&name) && entry.file_type()?.is_dir() => {
+                        containers.insert(name);
+                    }
+                    _ => log::debug!("Leaving {} alone", entry.path().display()),
+                }
+            }
+        }
+
+        let mut swept = 0;
+        for container in containers {
+            match self.sweep_container(&container) {
+                Ok(()) => swept += 1,
+                Err(e) => log::warn!("Failed to clean up {}: {}", container, e),
+            }
+        }
+
+        Ok(swept)
+    }
+
+    /// Unmount whatever is still mounted in a container's directory and
+    /// delete it, along with its scratch directory unless writes are kept.
+    /// Directories are only deleted once nothing is mounted in them.
+    fn sweep_container(&self, container: &str) -> io::Result<()> {
+        let dir = self.mount_dir.join(container);
+        for mountpoint in [dir.join("rootfs"), dir.join("lower")] {
+            detach(&mountpoint)?;
+            ignore_not_found(fs::remove_dir(&mountpoint))?;
+        }
+        ignore_not_found(fs::remove_dir(&dir))?;
+
+        let scratch = self.scratch_dir.join(container);
+        if self.keep_writes {
+            // The work directory is only of use to a mounted overlay
+            ignore_not_found(fs::remove_dir_all(scratch.join("work")))
+        } else {
+            ignore_not_found(fs::remove_dir_all(&scratch))
+        }
+    }
+}
+
+/// Pick a name for a new container
+pub fn container_name() -> String {
+    format!("{}{}", CONTAINER_PREFIX, rand::random::<u32>())
+}
+
+/// Whether `name` is one `container_name` picks
+fn is_container_name(name: &str) -> bool {
+    name.strip_prefix(CONTAINER_PREFIX)
+        .is_some_and(|id| !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Lazily unmount whatever is mounted at `mountpoint`, if anything
+fn detach(mountpoint: &Path) -> io::Result<()> {
+    match umount2(mountpoint, MntFlags::MNT_DETACH) {
+        // Not a mount point, or not there at all
+        Ok(()) | Err(Errno::EINVAL) | Err(Errno::ENOENT) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn ignore_not_found(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("silo-registry-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn restart_only_sweeps_containers() {
+        let dir = temp_dir("sweep");
+        let mount_dir = dir.join("mounts");
+        let scratch_dir = dir.join("scratch");
+
+        // Left behind by a container of a previous run
+        fs::create_dir_all(mount_dir.join("container-1/lower")).unwrap();
+        fs::create_dir_all(scratch_dir.join("container-1/upper")).unwrap();
+        fs::write(scratch_dir.join("container-1/upper/written"), b"data").unwrap();
+
+        // Not the registry's
+        fs::create_dir_all(mount_dir.join("other")).unwrap();
+        fs::create_dir_all(scratch_dir.join("cache")).unwrap();
+        fs::write(scratch_dir.join("cache/blob"), b"blob").unwrap();
+        fs::write(scratch_dir.join("silo.db"), b"db").unwrap();
+        fs::write(scratch_dir.join("container-2"), b"file").unwrap();
+        fs::create_dir_all(scratch_dir.join("container-x")).unwrap();
+
+        MountRegistry::new(mount_dir.clone(), scratch_dir.clone(), false).unwrap();
+
+        assert!(!mount_dir.join("container-1").exists());
+        assert!(!scratch_dir.join("container-1").exists());
+        assert!(mount_dir.join("other").is_dir());
+        assert_eq!(fs::read(scratch_dir.join("cache/blob")).unwrap(), b"blob");
+        assert_eq!(fs::read(scratch_dir.join("silo.db")).unwrap(), b"db");
+        assert_eq!(fs::read(scratch_dir.join("container-2")).unwrap(), b"file");
+        assert!(scratch_dir.join("container-x").is_dir());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn container_names() {
+        assert!(is_container_name(&container_name()));
+        assert!(is_container_name("container-42"));
+        for name in ["container-", "container-4x", "silo.db", "cache", "..", ""] {
+            assert!(!is_container_name(name), "{}", name);
+        }
+    }
+}
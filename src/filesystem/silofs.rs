@@ -1,72 +1,155 @@
+use crate::filesystem::disk_cache::{DiskCache, DiskCacheConfig};
 use crate::filesystem::mount::SiloFSMount;
+use crate::filesystem::pool::{IndexerPool, PoolConfig};
+use crate::filesystem::trace::AccessTrace;
+use crate::protocol::EntryName;
 use dashmap::DashMap;
-use fuser::MountOption;
+use fuser::{BackgroundSession, FileType, MountOption, FUSE_ROOT_ID};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::io::{self, Read, Write};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::sync::{Arc, Mutex};
-use std::thread;
+use std::time::Duration;
 
 /// Time-to-live for file system entries
 pub const TTL: std::time::Duration = std::time::Duration::from_secs(20);
 
+/// Granularity at which file contents are fetched from the indexer and cached
+pub const BLOCK_SIZE: u64 = 128 * 1024;
+
+/// Unit of the sizes reported by `statfs`
+pub const STATFS_BLOCK_SIZE: u64 = 4096;
+
+/// How long the kernel may take to initialize a new mount
+const MOUNT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// File contents shared by every mounted image, keyed by (hash, block index)
+/// and bounded by the total number of bytes held
+pub type BlockCache = moka::sync::Cache<(String, u64), Arc<Vec<u8>>>;
+
 /// Data structure for serializing and deserializing file system metadata
 #[derive(Serialize, Deserialize)]
 pub struct DatatoSend {
-    pub directory_cache: HashMap<u64, HashMap<String, u64>>,
+    /// Entries of every directory as (name, inode)
+    pub directory_cache: HashMap<u64, Vec<(EntryName, u64)>>,
     pub file_attr_cache: HashMap<u64, fuser::FileAttr>,
     pub inode_to_hash: HashMap<u64, String>,
+    /// Chunks of the files stored in chunks, by file hash, as (hash, length)
+    pub chunks: HashMap<String, Vec<(String, u64)>>,
+    /// Extended attributes of the inodes that have any
+    #[serde(default)]
+    pub xattrs: HashMap<u64, BTreeMap<String, Vec<u8>>>,
 }
 
 /// In-memory representation of image data
 pub struct ImageData {
-    pub directory_cache: HashMap<u64, HashMap<String, u64>>,
+    pub directory_cache: HashMap<u64, HashMap<OsString, u64>>,
+    /// Parent of every directory, the root being its own parent
+    pub parents: HashMap<u64, u64>,
+    /// Entries of every directory in the order they are listed
+    pub listings: HashMap<u64, Arc<Vec<DirEntry>>>,
     pub file_attr_cache: HashMap<u64, fuser::FileAttr>,
     pub inode_to_hash: HashMap<u64, String>,
-    pub content_cache: DashMap<String, Vec<u8>>,
+    /// Chunks of the files stored in chunks, by file hash, in file order
+    pub chunks: HashMap<String, Vec<Chunk>>,
+    pub xattrs: HashMap<u64, BTreeMap<String, Vec<u8>>>,
+    /// Blocks of `STATFS_BLOCK_SIZE` bytes taken by the contents of every
+    /// inode
+    pub total_blocks: u64,
+    pub cache_stats: CacheStats,
+    /// Number of blobs received from the indexer that did not match their hash
+    pub integrity_failures: AtomicU64,
+}
+
+/// Entry of a directory listing
+pub struct DirEntry {
+    pub ino: u64,
+    pub kind: FileType,
+    pub name: OsString,
+}
+
+/// Piece of a file stored as its own blob
+pub struct Chunk {
+    pub hash: String,
+    /// Position of the chunk in the file
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Block cache hit and miss counters of a single image
+#[derive(Default)]
+pub struct CacheStats {
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
 }
 
 /// Main structure for SiloFS
 pub struct SiloFS {
-    stream: Arc<Mutex<std::net::TcpStream>>,
+    pool: Arc<IndexerPool>,
+    disk_cache: Arc<DiskCache>,
+    block_cache: BlockCache,
     images: DashMap<String, Arc<ImageData>>,
 }
 
 impl SiloFS {
-    /// Create a new SiloFS instance
-    pub fn new(tcp_addr: &str) -> io::Result<Self> {
-        let stream = std::net::TcpStream::connect(tcp_addr).map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::ConnectionRefused,
-                format!("Failed to connect to {}: {}", tcp_addr, e),
-            )
-        })?;
-
-        stream.set_nodelay(true).map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to set TCP_NODELAY: {}", e),
-            )
-        })?;
+    /// Create a new SiloFS instance, `tcp_addrs` lists the indexer followed
+    /// by the fallbacks to use when it is unreachable, and `memory_cache_bytes`
+    /// bounds the file contents kept in memory across all images
+    pub fn new(
+        tcp_addrs: Vec<String>,
+        pool_config: PoolConfig,
+        cache_config: DiskCacheConfig,
+        memory_cache_bytes: u64,
+    ) -> io::Result<Self> {
+        let pool = IndexerPool::new(tcp_addrs, pool_config)?;
+        let disk_cache = DiskCache::open(cache_config)?;
 
         Ok(SiloFS {
-            stream: Arc::new(Mutex::new(stream)),
+            pool: Arc::new(pool),
+            disk_cache: Arc::new(disk_cache),
+            block_cache: BlockCache::builder()
+                .max_capacity(memory_cache_bytes)
+                .weigher(|_, block: &Arc<Vec<u8>>| block.len().try_into().unwrap_or(u32::MAX))
+                .build(),
             images: DashMap::new(),
         })
     }
 
-    /// Mount an image at a specified location
+    /// Mount an image at a specified location. With `verify`, every blob
+    /// fetched from the indexer is checked against its hash and reads of
+    /// blobs that do not match fail with `EIO`. The image stays mounted until
+    /// the returned session is dropped.
     pub fn mount(
         &self,
         image_name: &str,
-        mount_location: &str,
-    ) -> io::Result<thread::JoinHandle<()>> {
+        mount_location: &Path,
+        verify: bool,
+    ) -> io::Result<BackgroundSession> {
         let image_data = self.load_or_get_image_data(image_name)?;
-        let fs = SiloFSMount {
-            stream: self.stream.clone(),
+        let mut fs = SiloFSMount {
+            image_name: image_name.to_string(),
+            pool: self.pool.clone(),
+            disk_cache: self.disk_cache.clone(),
+            block_cache: self.block_cache.clone(),
+            verify,
             image_data,
+            trace: Arc::new(Mutex::new(AccessTrace::default())),
+            ready: None,
+            dir_handles: HashMap::new(),
+            next_dir_handle: 1,
         };
 
+        // Start fetching what the previous runs of this image read while the
+        // container boots
+        match self.pool.get_trace(image_name) {
+            Ok(inodes) => fs.prefetch(inodes),
+            Err(e) => log::warn!("Failed to load access trace for {}: {}", image_name, e),
+        }
+
         let options = vec![
             MountOption::RO,
             MountOption::FSName("silofs".to_string()),
@@ -75,14 +158,54 @@ impl SiloFS {
             MountOption::Exec,
         ];
 
-        let mount_location = mount_location.to_string();
-        let handle = thread::spawn(move || {
-            if let Err(e) = fuser::mount2(fs, mount_location.clone(), &options) {
-                log::error!("Failed to mount {}: {}", mount_location, e);
-            }
-        });
+        // Only the session's own copy holds the sender, so it is dropped if
+        // the session ends before the kernel initialized it
+        let (ready, initialized) = mpsc::channel();
+        fs.ready = Some(ready);
+
+        let session = fuser::spawn_mount2(fs, mount_location, &options).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to mount {}: {}", mount_location.display(), e),
+            )
+        })?;
+
+        // The kernel mount is in place once `spawn_mount2` returns, but
+        // nothing is served until the session answered the kernel's init
+        match initialized.recv_timeout(MOUNT_TIMEOUT) {
+            Ok(()) => Ok(session),
+            Err(RecvTimeoutError::Disconnected) => Err(io::Error::other(format!(
+                "FUSE session of {} ended before it was initialized",
+                mount_location.display()
+            ))),
+            Err(RecvTimeoutError::Timeout) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "{} was not initialized within {:?}",
+                    mount_location.display(),
+                    MOUNT_TIMEOUT
+                ),
+            )),
+        }
+    }
+
+    /// Block cache hits and misses of an image since it was first mounted
+    pub fn cache_stats(&self, image_name: &str) -> Option<(u64, u64)> {
+        self.images.get(image_name).map(|image_data| {
+            let stats = &image_data.cache_stats;
+            (
+                stats.hits.load(Ordering::Relaxed),
+                stats.misses.load(Ordering::Relaxed),
+            )
+        })
+    }
 
-        Ok(handle)
+    /// Number of blobs of an image that failed integrity verification since
+    /// it was first mounted
+    pub fn integrity_failures(&self, image_name: &str) -> Option<u64> {
+        self.images
+            .get(image_name)
+            .map(|image_data| image_data.integrity_failures.load(Ordering::Relaxed))
     }
 
     /// Load or get image data from cache
@@ -101,31 +224,7 @@ impl SiloFS {
     fn load_cache(&self, image_name: &str) -> io::Result<Arc<ImageData>> {
         log::info!("Loading {} cache from indexer...", image_name);
 
-        let request = format!("GET_DATA:{}", image_name);
-
-        let mut stream = self.stream.lock().map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to lock stream: {}", e),
-            )
-        })?;
-        stream.write_all(request.as_bytes()).map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to write request: {}", e),
-            )
-        })?;
-
-        let mut size_buf = [0u8; 8];
-        stream.read_exact(&mut size_buf).map_err(|e| {
-            io::Error::new(io::ErrorKind::Other, format!("Failed to read size: {}", e))
-        })?;
-        let size = u64::from_be_bytes(size_buf);
-
-        let mut data = vec![0u8; size as usize];
-        stream.read_exact(&mut data).map_err(|e| {
-            io::Error::new(io::ErrorKind::Other, format!("Failed to read data: {}", e))
-        })?;
+        let data = self.pool.get_image_data(image_name)?;
 
         let data: DatatoSend = serde_json::from_slice(&data).map_err(|e| {
             io::Error::new(
@@ -136,11 +235,126 @@ impl SiloFS {
 
         log::info!("Loaded {} cache from indexer", image_name);
 
+        let total_blocks = data
+            .file_attr_cache
+            .values()
+            .map(|attr| attr.size.div_ceil(STATFS_BLOCK_SIZE))
+            .sum();
+
+        let directory_cache: HashMap<u64, HashMap<OsString, u64>> = data
+            .directory_cache
+            .into_iter()
+            .map(|(parent, children)| {
+                let children = children
+                    .into_iter()
+                    .map(|(name, ino)| (OsString::from(name), ino))
+                    .collect();
+                (parent, children)
+            })
+            .collect();
+        let DirectoryIndex { parents, listings } =
+            index_directories(&directory_cache, &data.file_attr_cache);
+
         Ok(Arc::new(ImageData {
-            content_cache: DashMap::new(),
-            directory_cache: data.directory_cache,
+            total_blocks,
+            cache_stats: CacheStats::default(),
+            integrity_failures: AtomicU64::new(0),
+            directory_cache,
+            parents,
+            listings,
             file_attr_cache: data.file_attr_cache,
             inode_to_hash: data.inode_to_hash,
+            xattrs: data.xattrs,
+            chunks: data
+                .chunks
+                .into_iter()
+                .map(|(file_hash, chunks)| {
+                    let mut offset = 0;
+                    let chunks = chunks
+                        .into_iter()
+                        .map(|(hash, length)| {
+                            let chunk = Chunk {
+                                hash,
+                                offset,
+                                length,
+                            };
+                            offset += length;
+                            chunk
+                        })
+                        .collect();
+                    (file_hash, chunks)
+                })
+                .collect(),
         }))
     }
 }
+
+/// Parent and listing of every directory of an image
+struct DirectoryIndex {
+    parents: HashMap<u64, u64>,
+    listings: HashMap<u64, Arc<Vec<DirEntry>>>,
+}
+
+/// Parent of every directory, and the listing of every directory: `.` and
+/// `..` first, then subdirectories and then other entries, each by name.
+/// Listings are built once so their order, and the offsets `readdir` hands
+/// out into them, stay the same for as long as the image is loaded.
+fn index_directories(
+    directory_cache: &HashMap<u64, HashMap<OsString, u64>>,
+    file_attr_cache: &HashMap<u64, fuser::FileAttr>,
+) -> DirectoryIndex {
+    let is_dir = |ino: &u64| {
+        file_attr_cache
+            .get(ino)
+            .is_some_and(|attr| attr.kind == FileType::Directory)
+    };
+
+    let mut parents = HashMap::from([(FUSE_ROOT_ID, FUSE_ROOT_ID)]);
+    for (&parent, children) in directory_cache {
+        for &ino in children.values().filter(|ino| is_dir(ino)) {
+            parents.entry(ino).or_insert(parent);
+        }
+    }
+
+    let listings = file_attr_cache
+        .values()
+        .filter(|attr| attr.kind == FileType::Directory)
+        .map(|attr| {
+            // Empty directories have no entries recorded
+            let mut children: Vec<_> = directory_cache
+                .get(&attr.ino)
+                .into_iter()
+                .flatten()
+                .filter_map(|(name, &ino)| {
+                    Some(DirEntry {
+                        ino,
+                        kind: file_attr_cache.get(&ino)?.kind,
+                        name: name.clone(),
+                    })
+                })
+                .collect();
+            children.sort_unstable_by(|a, b| {
+                (a.kind != FileType::Directory)
+                    .cmp(&(b.kind != FileType::Directory))
+                    .then_with(|| a.name.cmp(&b.name))
+            });
+
+            let mut entries = Vec::with_capacity(children.len() + 2);
+            entries.push(DirEntry {
+                ino: attr.ino,
+                kind: FileType::Directory,
+                name: OsString::from("."),
+            });
+            entries.push(DirEntry {
+                ino: parents.get(&attr.ino).copied().unwrap_or(attr.ino),
+                kind: FileType::Directory,
+                name: OsString::from(".."),
+            });
+            entries.extend(children);
+
+            (attr.ino, Arc::new(entries))
+        })
+        .collect();
+
+    DirectoryIndex { parents, listings }
+}
@@ -0,0 +1,24 @@
+use crate::protocol::MAX_TRACE_LEN;
+use std::collections::HashSet;
+
+/// Inodes whose contents a mount read, in the order they were first read.
+/// Stored per image in the indexer and replayed as prefetches on the next
+/// mount of that image.
+#[derive(Default)]
+pub struct AccessTrace {
+    seen: HashSet<u64>,
+    inodes: Vec<u64>,
+}
+
+impl AccessTrace {
+    /// Record a read of `ino`, ignoring inodes already seen
+    pub fn record(&mut self, ino: u64) {
+        if self.inodes.len() < MAX_TRACE_LEN && self.seen.insert(ino) {
+            self.inodes.push(ino);
+        }
+    }
+
+    pub fn inodes(&self) -> &[u64] {
+        &self.inodes
+    }
+}
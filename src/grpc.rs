@@ -1,9 +1,11 @@
 use crate::container::run_podman_container;
 use crate::db::{Output, Task};
+use crate::filesystem::registry::{container_name, MountRegistry};
 use crate::filesystem::silofs::SiloFS;
 use colored::*;
 use silo::silo_server::Silo;
 use silo::{GetPackageRequest, GetPackageResponse};
+use std::sync::Arc;
 use tonic::{Request, Response, Status};
 
 pub mod silo {
@@ -12,7 +14,10 @@ pub mod silo {
 
 pub struct TheSilo {
     pub host_link: String,
-    pub filesystem: SiloFS,
+    /// Whether mounted images check contents against their hash
+    pub verify_content: bool,
+    pub filesystem: Arc<SiloFS>,
+    pub mounts: Arc<MountRegistry>,
 }
 
 #[tonic::async_trait]
@@ -27,19 +32,36 @@ impl Silo for TheSilo {
 
         let request_data = request.into_inner();
 
-        let container_name = format!("container-{}", rand::random::<u32>());
-        let mount_path = &format!("/tmp/{}", container_name);
+        let container_name = container_name();
 
         println!(
             "{}",
             format!("Creating container {}...", container_name).bright_yellow()
         );
 
-        std::fs::create_dir_all(mount_path).unwrap();
-
-        self.filesystem
-            .mount(&request_data.image_name, mount_path)
-            .unwrap();
+        // Mounting fetches the image metadata and waits for the kernel, so it
+        // runs off the async workers. Unmounted when dropped, however this
+        // request ends.
+        let mount = {
+            let mounts = self.mounts.clone();
+            let filesystem = self.filesystem.clone();
+            let container_name = container_name.clone();
+            let image_name = request_data.image_name.clone();
+            let verify_content = self.verify_content;
+            tokio::task::spawn_blocking(move || {
+                mounts.mount(&container_name, &filesystem, &image_name, verify_content)
+            })
+        }
+        .await
+        .map_err(|e| Status::internal(format!("Failed to mount {}: {}", container_name, e)))?
+        .map_err(|e| Status::internal(format!("Failed to mount {}: {}", container_name, e)))?;
+        let rootfs = mount.rootfs().to_str().ok_or_else(|| {
+            Status::internal(format!(
+                "Mount path {} of {} is not UTF-8",
+                mount.rootfs().display(),
+                container_name
+            ))
+        })?;
 
         // send the data to the HTTP server
         let task_id = reqwest::Client::new()
@@ -65,9 +87,16 @@ impl Silo for TheSilo {
             format!("Running {}...", container_name).bright_yellow()
         );
 
-        let container_result = run_podman_container(task_id, &self.host_link, mount_path)
-            .await
-            .unwrap();
+        let container_result = run_podman_container(task_id, &self.host_link, rootfs).await;
+
+        // Unmounting waits for the FUSE session to end
+        if let Err(e) = tokio::task::spawn_blocking(move || drop(mount)).await {
+            log::warn!("Failed to unmount {}: {}", container_name, e);
+        }
+
+        let container_result = container_result.map_err(|e| {
+            Status::internal(format!("Failed to run {}: {}", container_name, e))
+        })?;
 
         println!(
             "{}",
@@ -79,6 +108,27 @@ impl Silo for TheSilo {
             .bright_yellow()
         );
 
+        if let Some((hits, misses)) = self.filesystem.cache_stats(&request_data.image_name) {
+            log::info!(
+                "Block cache for {}: {} hits, {} misses",
+                request_data.image_name,
+                hits,
+                misses
+            );
+        }
+
+        if let Some(failures) = self
+            .filesystem
+            .integrity_failures(&request_data.image_name)
+            .filter(|&failures| failures > 0)
+        {
+            log::warn!(
+                "{} blobs of {} failed integrity verification",
+                failures,
+                request_data.image_name
+            );
+        }
+
         let python_result = reqwest::Client::new()
             .get(format!("{}/api/results/{}", self.host_link, task_id))
             .body(container_name)
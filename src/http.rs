@@ -1,6 +1,8 @@
 use crate::db::{Output, Task};
+use crate::filesystem::registry::MountRegistry;
 use actix_web::{web, HttpResponse, Scope};
 use rusqlite::Connection;
+use std::sync::Arc;
 use thiserror::Error;
 use tokio::sync::Mutex;
 
@@ -27,6 +29,7 @@ impl actix_web::ResponseError for AppError {
 
 pub struct AppState {
     pub db_connection: Mutex<Connection>,
+    pub mounts: Arc<MountRegistry>,
 }
 
 pub async fn add_task(
@@ -70,10 +73,16 @@ pub async fn get_result(
     Ok(HttpResponse::Ok().json(output))
 }
 
+/// The mounts of the running containers
+pub async fn get_mounts(data: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(data.mounts.active())
+}
+
 pub fn configure_routes() -> Scope {
     web::scope("/api")
         .route("/tasks", web::post().to(add_task))
         .route("/tasks/{task_id}", web::get().to(get_task))
         .route("/results/{task_id}", web::post().to(add_result))
         .route("/results/{task_id}", web::get().to(get_result))
+        .route("/mounts", web::get().to(get_mounts))
 }
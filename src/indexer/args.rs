@@ -5,19 +5,29 @@ use std::path::PathBuf;
 #[command(author, version, about, long_about = None)]
 pub struct Args {
     #[command(subcommand)]
-    pub command: Option<Commands>,
+    pub command: Commands,
 
-    #[arg(long, default_value = "127.0.0.1")]
+    #[arg(long, global = true, default_value = "127.0.0.1")]
     pub host: String,
 
-    #[arg(short, long, default_value_t = 8080)]
+    #[arg(short, long, global = true, default_value_t = 8080)]
     pub port: u16,
 
-    #[arg(short, long, default_value = "./data/content")]
+    #[arg(short, long, global = true, default_value = "./data/content")]
     pub storage: PathBuf,
 
-    #[arg(short, long, default_value = "./data/indexer.db")]
+    #[arg(short, long, global = true, default_value = "./data/indexer.db")]
     pub db: String,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Store large files as content-defined chunks to deduplicate similar files"
+    )]
+    pub chunking: bool,
+
+    #[arg(long, global = true, help = "Store new blobs compressed with zstd")]
+    pub compression: bool,
 }
 
 #[derive(Subcommand, Debug)]
@@ -25,6 +35,62 @@ pub enum Commands {
     #[clap(name = "index", about = "Index a podman image")]
     Index { image_name: String },
 
+    #[clap(
+        name = "index-archive",
+        about = "Index an image from an OCI layout or a docker/podman save tarball"
+    )]
+    IndexArchive { path: PathBuf, image_name: String },
+
     #[clap(name = "list", about = "List indexed podman images")]
     List,
-}
\ No newline at end of file
+
+    #[clap(name = "list-dir", about = "List a directory of an indexed image")]
+    ListDir {
+        image_name: String,
+        #[arg(default_value = "/")]
+        path: String,
+    },
+
+    #[clap(
+        name = "find-hash",
+        about = "List the indexed images containing a blob"
+    )]
+    FindHash { hash: String },
+
+    #[clap(name = "remove", about = "Remove an indexed image")]
+    Remove { image_name: String },
+
+    #[clap(name = "gc", about = "Delete content no indexed image references")]
+    Gc,
+
+    #[clap(
+        name = "verify",
+        about = "Check that stored content matches the metadata of indexed images"
+    )]
+    Verify {
+        #[arg(help = "Image to check, every indexed image if omitted")]
+        image_name: Option<String>,
+
+        #[arg(
+            long,
+            help = "Store missing or corrupt content again from the source image"
+        )]
+        repair: bool,
+
+        #[arg(
+            long,
+            requires = "repair",
+            help = "OCI layout or saved image tarball to repair from instead of pulling the image"
+        )]
+        source: Option<PathBuf>,
+    },
+
+    #[clap(name = "serve", about = "Serve indexed images to silo")]
+    Serve,
+
+    #[clap(
+        name = "shell",
+        about = "Serve indexed images and read indexing commands from stdin"
+    )]
+    Shell,
+}
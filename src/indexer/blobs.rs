@@ -0,0 +1,71 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use crate::protocol::MAX_RANGE_LEN;
+
+/// Suffix of blobs stored as a zstd frame
+pub const COMPRESSED_SUFFIX: &str = ".zst";
+
+/// Largest blob stored compressed. A compressed blob is decompressed whole to
+/// serve a range of it, so larger blobs are stored as is; chunking keeps
+/// every blob of large files under this size.
+pub const MAX_COMPRESSED_SIZE: u64 = MAX_RANGE_LEN as u64;
+
+/// zstd level blobs are stored with
+pub const COMPRESSION_LEVEL: i32 = 3;
+
+/// Path of a blob stored as is
+pub fn raw_path(folder: &Path, hash: &str) -> PathBuf {
+    folder.join(hash)
+}
+
+/// Path of a blob stored compressed
+pub fn compressed_path(folder: &Path, hash: &str) -> PathBuf {
+    folder.join(format!("{}{}", hash, COMPRESSED_SUFFIX))
+}
+
+/// Whether a blob is stored, compressed or not
+pub fn exists(folder: &Path, hash: &str) -> bool {
+    raw_path(folder, hash).exists() || compressed_path(folder, hash).exists()
+}
+
+/// Open a stored blob to read its uncompressed content, or `None` if it is
+/// not stored
+pub fn open(folder: &Path, hash: &str) -> io::Result<Option<Box<dyn Read>>> {
+    match File::open(raw_path(folder, hash)) {
+        Ok(file) => return Ok(Some(Box::new(file))),
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        Err(_) => {}
+    }
+
+    match File::open(compressed_path(folder, hash)) {
+        Ok(file) => Ok(Some(Box::new(zstd::Decoder::new(file)?))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Delete a blob in whichever form it is stored
+pub fn remove(folder: &Path, hash: &str) -> io::Result<()> {
+    for path in [raw_path(folder, hash), compressed_path(folder, hash)] {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Hash of the blob stored under `file_name`, if it is a blob
+pub fn hash_of(file_name: &str) -> Option<&str> {
+    let hash = file_name
+        .strip_suffix(COMPRESSED_SUFFIX)
+        .unwrap_or(file_name);
+
+    if hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(hash)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,213 @@
+use std::io::{self, Read};
+
+/// Smallest chunk cut, except for the last chunk of a file
+pub const MIN_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Size chunks are cut at on average
+pub const AVG_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Largest chunk cut, a chunk is forced at this size if no boundary is found
+pub const MAX_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Masks checked before and after reaching the average size. The first has
+/// more bits set, making cuts unlikely in small chunks, and the second fewer,
+/// making them likely in large ones (FastCDC's normalized chunking).
+const MASK_SMALL: u64 = mask(AVG_CHUNK_SIZE.trailing_zeros() + 2);
+const MASK_LARGE: u64 = mask(AVG_CHUNK_SIZE.trailing_zeros() - 2);
+
+/// Random values mixed into the rolling hash for every byte. Generated with
+/// a fixed seed, so chunk boundaries are the same across builds and runs.
+const GEAR: [u64; 256] = gear_table();
+
+/// Mask with the `bits` highest bits set, which are the best mixed bits of
+/// the gear hash
+const fn mask(bits: u32) -> u64 {
+    !0u64 << (64 - bits)
+}
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x5349_4c4f_4344_4331;
+    let mut i = 0;
+    while i < table.len() {
+        // splitmix64
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Length of the first chunk of `data`, which must hold at least
+/// `MAX_CHUNK_SIZE` bytes unless it is the end of the input
+fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len();
+    }
+
+    let end = data.len().min(MAX_CHUNK_SIZE);
+    let normal = end.min(AVG_CHUNK_SIZE);
+    let mut hash: u64 = 0;
+
+    for (i, &byte) in data.iter().enumerate().take(end).skip(MIN_CHUNK_SIZE) {
+        hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+        let mask = if i < normal { MASK_SMALL } else { MASK_LARGE };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+
+    end
+}
+
+/// Splits a stream into content-defined chunks, so that an insertion or
+/// deletion only changes the chunks around it. Memory use is bounded by
+/// `MAX_CHUNK_SIZE` whatever the size of the stream.
+pub struct Chunker<R> {
+    reader: R,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> Chunker<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buf: Vec::with_capacity(MAX_CHUNK_SIZE),
+            eof: false,
+        }
+    }
+
+    /// Read until a full chunk is buffered or the stream ends
+    fn fill(&mut self) -> io::Result<()> {
+        while !self.eof && self.buf.len() < MAX_CHUNK_SIZE {
+            let len = self.buf.len();
+            self.buf.resize(MAX_CHUNK_SIZE, 0);
+
+            match self.reader.read(&mut self.buf[len..]) {
+                Ok(0) => {
+                    self.buf.truncate(len);
+                    self.eof = true;
+                }
+                Ok(n) => self.buf.truncate(len + n),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => self.buf.truncate(len),
+                Err(e) => {
+                    self.buf.truncate(len);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for Chunker<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(e) = self.fill() {
+            return Some(Err(e));
+        }
+
+        if self.buf.is_empty() {
+            return None;
+        }
+
+        let cut = cut_point(&self.buf);
+        Some(Ok(self.buf.drain(..cut).collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    /// Deterministic incompressible data
+    fn random_data(len: usize, mut state: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn chunks<R: Read>(reader: R) -> Vec<Vec<u8>> {
+        Chunker::new(reader).collect::<io::Result<_>>().unwrap()
+    }
+
+    /// Yields a few KiB per read, interrupting every other read
+    struct Trickle<'a> {
+        data: &'a [u8],
+        interrupt: bool,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            let len = buf.len().min(self.data.len()).min(4093);
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn chunks_cover_input_within_bounds() {
+        let data = random_data(4 * 1024 * 1024, 1);
+        let chunks = chunks(data.as_slice());
+
+        assert_eq!(chunks.concat(), data);
+        let (last, rest) = chunks.split_last().unwrap();
+        assert!(last.len() <= MAX_CHUNK_SIZE);
+        for chunk in rest {
+            assert!((MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&chunk.len()));
+        }
+    }
+
+    #[test]
+    fn boundaries_survive_prefix_insert() {
+        let data = random_data(4 * 1024 * 1024, 2);
+        let mut shifted = random_data(100, 3);
+        shifted.extend_from_slice(&data);
+
+        let before = chunks(data.as_slice());
+        let after = chunks(shifted.as_slice());
+        assert_eq!(after.concat(), shifted);
+
+        // Only the chunks up to the first boundary after the insertion change
+        let before: HashSet<_> = before.iter().collect();
+        let changed = after.iter().filter(|chunk| !before.contains(chunk)).count();
+        assert!(
+            changed <= 2,
+            "{} of {} chunks changed",
+            changed,
+            after.len()
+        );
+    }
+
+    #[test]
+    fn boundaries_do_not_depend_on_reads() {
+        let data = random_data(1024 * 1024, 4);
+        let trickled = chunks(Trickle {
+            data: &data,
+            interrupt: false,
+        });
+        assert_eq!(trickled, chunks(data.as_slice()));
+    }
+
+    #[test]
+    fn small_inputs() {
+        assert!(chunks(&[][..]).is_empty());
+        assert_eq!(chunks(&[1, 2, 3][..]), [vec![1, 2, 3]]);
+    }
+}
@@ -1,12 +1,29 @@
-use anyhow::Result;
-use log::{error, info};
-
+use anyhow::{Context, Result};
+use fuser::FileType;
+use log::{debug, error, info, warn};
+use nix::fcntl::{Flock, FlockArg};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::blobs;
 use crate::database::AppState;
-use crate::indexer::ContentIndexer;
+use crate::indexer::{ContentIndexer, RecordedMetadata};
+use crate::oci::{self, UnpackedImage};
+use crate::verify::{self, ContentChecker, Issue};
 
 pub async fn index_image(image_name: &str, state: &AppState) -> Result<()> {
-    let start_time = std::time::Instant::now();
+    let start_time = Instant::now();
+    let mount_path = prepare_container(image_name)?;
+
+    index_root(image_name, &mount_path, HashMap::new(), state, start_time).await
+}
 
+/// Pull an image, run it with the files silo needs added and mount its
+/// root filesystem
+fn prepare_container(image_name: &str) -> Result<PathBuf> {
     info!("Pulling image: {}", image_name);
     pull_image(image_name)?;
 
@@ -20,17 +37,69 @@ pub async fn index_image(image_name: &str, state: &AppState) -> Result<()> {
     install_python_libraries(&container_id)?;
 
     info!("Mounting container: {} ({})", image_name, container_id);
-    let mount_path = mount_container(&container_id)?;
+    mount_container(&container_id)
+}
+
+/// Index an image from an OCI image layout directory or a `docker save` /
+/// `podman save` tarball, without pulling or running it
+pub async fn index_archive(source: &Path, image_name: &str, state: &AppState) -> Result<()> {
+    let start_time = Instant::now();
+    let work_dir = std::env::temp_dir().join(format!("silo-index-{}", rand::random::<u32>()));
+
+    info!(
+        "Unpacking image: {} into {}",
+        source.display(),
+        work_dir.display()
+    );
+
+    let result: Result<()> = async {
+        let image = unpack_archive(source, image_name, &work_dir)?;
+        index_root(image_name, &image.rootfs, image.recorded, state, start_time).await
+    }
+    .await;
+
+    if let Err(e) = oci::remove_work_dir(&work_dir) {
+        warn!("Failed to remove {}: {:?}", work_dir.display(), e);
+    }
+
+    result
+}
 
-    let last_saved_inode = state.load_next_inode().await?;
+/// Unpack an image archive into `work_dir` with the files silo needs added
+fn unpack_archive(source: &Path, image_name: &str, work_dir: &Path) -> Result<UnpackedImage> {
+    let image = oci::unpack_image(source, work_dir)?;
 
-    let mut fs = ContentIndexer::new(image_name, last_saved_inode, state.output_folder.clone());
+    info!("Adding custom file to image: {}", image_name);
+    std::fs::copy("./common/silo.py", image.rootfs.join("silo.py"))?;
 
-    fs.total_files = walkdir::WalkDir::new(&mount_path).into_iter().count();
-    info!("Total files to process: {}", fs.total_files);
+    warn!(
+        "Python libraries are not installed when indexing from an archive, {} must already provide requests and cloudpickle",
+        image_name
+    );
+
+    Ok(image)
+}
+
+/// Index the unpacked root filesystem of an image and save it. `recorded`
+/// holds the metadata unpacking could not reproduce on disk.
+async fn index_root(
+    image_name: &str,
+    root: &Path,
+    recorded: HashMap<PathBuf, RecordedMetadata>,
+    state: &AppState,
+    start_time: Instant,
+) -> Result<()> {
+    // Keep `gc` from deleting the content written below before the image
+    // referencing it is saved
+    let _lock = lock_content(state, FlockArg::LockShared)?;
+
+    let mut fs = ContentIndexer::new(image_name, state.output_folder.clone());
+    fs.chunking = state.chunking;
+    fs.compression = state.compression;
+    fs.recorded = recorded;
 
     // Process files
-    fs.save_directory(&mount_path, 0)?;
+    fs.save_directory(root)?;
 
     let elapsed = start_time.elapsed().as_secs_f64();
 
@@ -40,7 +109,6 @@ pub async fn index_image(image_name: &str, state: &AppState) -> Result<()> {
     );
 
     state.save_to_sqlite(&fs).await?;
-    state.save_next_inode(fs.next_inode).await?;
 
     info!("Image indexed successfully!");
 
@@ -62,6 +130,278 @@ pub async fn list_images(state: &AppState) -> Result<()> {
     Ok(())
 }
 
+/// Print the entries of a directory in an indexed image
+pub async fn list_directory(image_name: &str, path: &str, state: &AppState) -> Result<()> {
+    let entries = state.list_directory(image_name, path).await?;
+
+    for (name, attr) in entries {
+        let kind = match attr.kind {
+            FileType::Directory => 'd',
+            FileType::Symlink => 'l',
+            FileType::NamedPipe => 'p',
+            FileType::CharDevice => 'c',
+            FileType::BlockDevice => 'b',
+            FileType::Socket => 's',
+            FileType::RegularFile => '-',
+        };
+        println!(
+            "{}{:04o} {:>12} {}",
+            kind,
+            attr.perm & 0o7777,
+            attr.size,
+            name.to_string_lossy()
+        );
+    }
+
+    Ok(())
+}
+
+/// Print the images containing a blob
+pub async fn find_hash(hash: &str, state: &AppState) -> Result<()> {
+    let image_names = state.find_images_with_hash(hash).await?;
+
+    if image_names.is_empty() {
+        println!("No indexed image contains {}.", hash);
+    } else {
+        println!("Images containing {}:", hash);
+        for (i, name) in image_names.iter().enumerate() {
+            println!("{}. {}", i + 1, name);
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn remove_image(image_name: &str, state: &AppState) -> Result<()> {
+    if !state.remove_image(image_name).await? {
+        anyhow::bail!("Image {} is not indexed", image_name);
+    }
+
+    println!(
+        "Removed image {}, run gc to delete content no longer referenced",
+        image_name
+    );
+    Ok(())
+}
+
+/// Delete the content no image references anymore. Safe to run while the
+/// TCP server is serving, or while another indexer process is indexing.
+pub async fn collect_garbage(state: &AppState) -> Result<()> {
+    let _lock = lock_content(state, FlockArg::LockExclusive)?;
+
+    let pruned = state.prune_chunks().await?;
+    debug!(
+        "Pruned {} chunk entries of files no longer referenced",
+        pruned
+    );
+
+    let live = state.get_referenced_hashes().await?;
+    info!("{} blobs are referenced by indexed images", live.len());
+
+    let mut removed = 0;
+    let mut reclaimed = 0;
+    for entry in fs::read_dir(&state.output_folder)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let metadata = entry.metadata()?;
+
+        // Blobs are written under a temporary name first, any left over
+        // belongs to an indexing run that did not finish
+        let leftover = name.starts_with("tmp-");
+        let unreferenced = blobs::hash_of(&name).is_some_and(|hash| !live.contains(hash));
+
+        if !metadata.is_file() || !(leftover || unreferenced) {
+            continue;
+        }
+
+        fs::remove_file(entry.path())?;
+        debug!("Removed unreferenced blob {}", name);
+        removed += 1;
+        reclaimed += metadata.len();
+    }
+
+    println!(
+        "Removed {} unreferenced blobs, reclaimed {} bytes",
+        removed, reclaimed
+    );
+    Ok(())
+}
+
+/// Check that the content store and the metadata of an image, or of every
+/// image, agree. With `repair`, damaged content is stored again from the
+/// image pulled with podman, or from the archive at `source`.
+pub async fn verify_images(
+    image_name: Option<&str>,
+    repair: bool,
+    source: Option<&Path>,
+    state: &AppState,
+) -> Result<()> {
+    // Keep `gc` from deleting content while it is checked or repaired
+    let _lock = lock_content(state, FlockArg::LockShared)?;
+
+    let image_names = match image_name {
+        Some(image_name) => vec![image_name.to_string()],
+        None => state.get_indexed_images().await?,
+    };
+
+    let mut checker = ContentChecker::new(&state.output_folder);
+    let mut problems = 0;
+
+    for image_name in &image_names {
+        let (directory, file_attr, inode_to_hash) = state.get_image_data(image_name).await?;
+        let chunks = state.get_image_chunks(image_name).await?;
+
+        let mut issues = verify::check_image(
+            &directory,
+            &file_attr,
+            &inode_to_hash,
+            &chunks,
+            &mut checker,
+        );
+        for issue in &issues {
+            println!("{}: {}", image_name, issue);
+        }
+
+        if repair && issues.iter().any(|issue| issue.damaged_content().is_some()) {
+            let restored = repair_image(image_name, source, &directory, &chunks, &issues, state)
+                .with_context(|| format!("Failed to repair {}", image_name))?;
+
+            for hash in &restored {
+                checker.forget(hash);
+            }
+            issues = verify::check_image(
+                &directory,
+                &file_attr,
+                &inode_to_hash,
+                &chunks,
+                &mut checker,
+            );
+            println!(
+                "{}: restored {} files, {} problems left",
+                image_name,
+                restored.len(),
+                issues.len()
+            );
+        }
+
+        if issues.is_empty() {
+            println!("{}: OK", image_name);
+        }
+        problems += issues.len();
+    }
+
+    // Unreferenced blobs are harmless, `gc` deletes them
+    let live = state.get_referenced_hashes().await?;
+    let mut orphans = 0;
+    for entry in fs::read_dir(&state.output_folder)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if blobs::hash_of(&name).is_some_and(|hash| !live.contains(hash)) {
+            debug!("Orphaned blob {}", name);
+            orphans += 1;
+        }
+    }
+    if orphans > 0 {
+        println!(
+            "{} blobs are not referenced by any image, run gc to delete them",
+            orphans
+        );
+    }
+
+    if problems > 0 {
+        anyhow::bail!(
+            "Found {} problems in {} images",
+            problems,
+            image_names.len()
+        );
+    }
+    println!("Verified {} images", image_names.len());
+    Ok(())
+}
+
+/// Store the damaged content of an image again from its source, returning
+/// the hashes of the files restored
+fn repair_image(
+    image_name: &str,
+    source: Option<&Path>,
+    directory: &HashMap<u64, HashMap<OsString, u64>>,
+    chunks: &HashMap<String, Vec<(String, u64)>>,
+    issues: &[Issue],
+    state: &AppState,
+) -> Result<HashSet<String>> {
+    let source = match source {
+        Some(source) => source,
+        None => {
+            let root = prepare_container(image_name)?;
+            return restore_content(image_name, &root, directory, chunks, issues, state);
+        }
+    };
+
+    let work_dir = std::env::temp_dir().join(format!("silo-repair-{}", rand::random::<u32>()));
+    let result = unpack_archive(source, image_name, &work_dir).and_then(|image| {
+        restore_content(image_name, &image.rootfs, directory, chunks, issues, state)
+    });
+
+    if let Err(e) = oci::remove_work_dir(&work_dir) {
+        warn!("Failed to remove {}: {:?}", work_dir.display(), e);
+    }
+
+    result
+}
+
+/// Store the damaged content of an image again from its unpacked root
+fn restore_content(
+    image_name: &str,
+    root: &Path,
+    directory: &HashMap<u64, HashMap<OsString, u64>>,
+    chunks: &HashMap<String, Vec<(String, u64)>>,
+    issues: &[Issue],
+    state: &AppState,
+) -> Result<HashSet<String>> {
+    let paths = verify::inode_paths(directory);
+    let mut fs = ContentIndexer::new(image_name, state.output_folder.clone());
+    fs.compression = state.compression;
+
+    let mut restored = HashSet::new();
+    for (ino, hash, damaged) in issues.iter().filter_map(Issue::damaged_content) {
+        if restored.contains(hash) {
+            continue;
+        }
+
+        let Some(path) = paths.get(&ino) else {
+            warn!("Cannot restore inode {}, it has no path", ino);
+            continue;
+        };
+        let path = root.join(path);
+
+        match fs.restore(&path, hash, damaged, chunks.contains_key(hash)) {
+            Ok(true) => {
+                println!("Restored {} from {}", hash, path.display());
+                restored.insert(hash.to_string());
+            }
+            Ok(false) => println!(
+                "Cannot restore {}, {} has different content in the source",
+                hash,
+                path.display()
+            ),
+            Err(e) => println!("Cannot restore {} from {}: {}", hash, path.display(), e),
+        }
+    }
+
+    Ok(restored)
+}
+
+/// Take a lock on the content folder, shared while indexing and exclusive
+/// while collecting garbage. The lock is held across processes until the
+/// returned guard is dropped.
+fn lock_content(state: &AppState, kind: FlockArg) -> Result<Flock<File>> {
+    let lock_path = state.output_folder.join(".lock");
+    let file = File::create(&lock_path)
+        .with_context(|| format!("Failed to open {}", lock_path.display()))?;
+
+    Flock::lock(file, kind)
+        .map_err(|(_, e)| anyhow::anyhow!("Failed to lock {}: {}", lock_path.display(), e))
+}
+
 fn pull_image(image_name: &str) -> Result<()> {
     let output = std::process::Command::new("sudo")
         .args(&["podman", "pull", image_name])
@@ -1,27 +1,51 @@
 use anyhow::{Context, Result};
-use rusqlite::{params, Connection};
-use std::collections::HashMap;
+use log::info;
+use rusqlite::types::ValueRef;
+use rusqlite::{params, Connection, OptionalExtension, Row, Transaction};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsString;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::sync::Mutex;
 
 use crate::indexer::ContentIndexer;
-use fuser::FileAttr;
+use fuser::{FileAttr, FileType, FUSE_ROOT_ID};
+
+/// Directory entries by parent, attributes by inode and content hashes by
+/// inode of an image
+pub type ImageTables = (
+    HashMap<u64, HashMap<OsString, u64>>,
+    HashMap<u64, FileAttr>,
+    HashMap<u64, String>,
+);
 
 #[derive(Clone)]
 pub struct AppState {
     pub db: Arc<Mutex<Connection>>,
     pub output_folder: PathBuf,
+    /// Whether new images are indexed with content-defined chunking
+    pub chunking: bool,
+    /// Whether new blobs are stored compressed
+    pub compression: bool,
 }
 
 impl AppState {
-    pub async fn new(db_path: String, output_folder: PathBuf) -> Result<Self> {
+    pub async fn new(
+        db_path: String,
+        output_folder: PathBuf,
+        chunking: bool,
+        compression: bool,
+    ) -> Result<Self> {
         let conn = Connection::open(&db_path)
             .with_context(|| format!("Failed to open database at {}", db_path))?;
 
         let app_state = Self {
             db: Arc::new(Mutex::new(conn)),
             output_folder,
+            chunking,
+            compression,
         };
 
         app_state.initialize_database().await?;
@@ -30,77 +54,78 @@ impl AppState {
     }
 
     async fn initialize_database(&self) -> Result<()> {
-        let conn = self.db.lock().await;
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS indexer (
-                id TEXT PRIMARY KEY,
-                next_inode INTEGER,
-                directory TEXT,
-                file_attr TEXT,
-                inode_to_hash TEXT
-            )",
-            [],
-        )?;
+        let mut conn = self.db.lock().await;
+        conn.execute_batch("PRAGMA foreign_keys = ON")?;
 
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS next_inode (
-                id TEXT PRIMARY KEY,
-                next_inode INTEGER
-            )",
-            [],
-        )?;
+        let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version > MIGRATIONS.len() {
+            anyhow::bail!(
+                "Database schema version {} is newer than this indexer supports ({})",
+                version,
+                MIGRATIONS.len()
+            );
+        }
+
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            info!("Migrating database schema to version {}", i + 1);
+
+            let tx = conn.transaction()?;
+            migration(&tx).with_context(|| format!("Migration {} failed", i + 1))?;
+            tx.pragma_update(None, "user_version", i + 1)?;
+            tx.commit()?;
+        }
 
         Ok(())
     }
 
     pub async fn save_to_sqlite(&self, fs: &ContentIndexer) -> Result<()> {
-        let conn = self.db.lock().await;
-        conn.execute(
-            "INSERT OR REPLACE INTO indexer (id, next_inode, directory, file_attr, inode_to_hash) 
-             VALUES (?1, ?2, ?3, ?4, ?5)",
-            params![
-                fs.image_name,
-                fs.next_inode,
-                serde_json::to_string(&fs.directory)?,
-                serde_json::to_string(&fs.file_attr)?,
-                serde_json::to_string(&fs.inode_to_hash)?
-            ],
+        let mut conn = self.db.lock().await;
+        let tx = conn.transaction()?;
+
+        insert_image(
+            &tx,
+            &fs.image_name,
+            &fs.directory,
+            &fs.file_attr,
+            &fs.inode_to_hash,
         )?;
+        insert_chunks(&tx, &fs.file_chunks)?;
+        insert_xattrs(&tx, &fs.image_name, &fs.xattrs)?;
 
+        tx.commit()?;
         Ok(())
     }
 
-    pub async fn save_next_inode(&self, next_inode: u64) -> Result<()> {
+    pub async fn save_trace(&self, image_name: &str, inodes: &[u64]) -> Result<()> {
         let conn = self.db.lock().await;
         conn.execute(
-            "INSERT OR REPLACE INTO next_inode (id, next_inode) 
+            "INSERT OR REPLACE INTO traces (id, inodes) 
              VALUES (?1, ?2)",
-            params!["next_inode", next_inode],
+            params![image_name, serde_json::to_string(inodes)?],
         )?;
 
         Ok(())
     }
 
-    pub async fn load_next_inode(&self) -> Result<u64> {
+    pub async fn get_trace(&self, image_name: &str) -> Result<Vec<u64>> {
         let conn = self.db.lock().await;
-        conn.query_row(
-            "SELECT next_inode FROM next_inode WHERE id = 'next_inode'",
-            [],
-            |row| row.get(0),
-        )
-        .or_else(|_| {
-            conn.execute(
-                "INSERT INTO next_inode (id, next_inode) 
-                 VALUES ('next_inode', 1)",
-                [],
-            )?;
-            Ok(1)
-        })
+        let inodes: Option<String> = conn
+            .query_row(
+                "SELECT inodes FROM traces WHERE id = ?1",
+                params![image_name],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        match inodes {
+            Some(inodes) => Ok(serde_json::from_str(&inodes)?),
+            None => Ok(Vec::new()),
+        }
     }
 
     pub async fn get_indexed_images(&self) -> Result<Vec<String>> {
         let conn = self.db.lock().await;
-        let mut stmt = conn.prepare("SELECT id FROM indexer")?;
+        let mut stmt = conn.prepare("SELECT name FROM images ORDER BY name")?;
         let image_names: Vec<String> = stmt
             .query_map([], |row| row.get(0))?
             .collect::<Result<Vec<String>, _>>()?;
@@ -108,34 +133,900 @@ impl AppState {
         Ok(image_names)
     }
 
-    pub async fn get_image_data(
+    /// Delete an image and its access trace, returning whether it existed.
+    /// Its content stays on disk until the next `gc`.
+    pub async fn remove_image(&self, image_name: &str) -> Result<bool> {
+        let mut conn = self.db.lock().await;
+        let tx = conn.transaction()?;
+
+        // Inodes, directory entries and content references cascade
+        let removed = tx.execute("DELETE FROM images WHERE name = ?1", params![image_name])?;
+        tx.execute("DELETE FROM traces WHERE id = ?1", params![image_name])?;
+        tx.commit()?;
+
+        Ok(removed > 0)
+    }
+
+    /// Drop the chunk lists of files no image references anymore, returning
+    /// the number of chunk entries dropped
+    pub async fn prune_chunks(&self) -> Result<usize> {
+        let conn = self.db.lock().await;
+        let pruned = conn.execute(
+            "DELETE FROM chunks WHERE file_hash NOT IN (SELECT hash FROM content)",
+            [],
+        )?;
+
+        Ok(pruned)
+    }
+
+    /// Hashes of the blobs referenced by any indexed image, either whole
+    /// files or chunks of them
+    pub async fn get_referenced_hashes(&self) -> Result<HashSet<String>> {
+        let conn = self.db.lock().await;
+        let mut stmt = conn.prepare(
+            "SELECT hash FROM content
+             UNION
+             SELECT chunk_hash FROM chunks WHERE file_hash IN (SELECT hash FROM content)",
+        )?;
+        let hashes = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<HashSet<String>, _>>()?;
+
+        Ok(hashes)
+    }
+
+    /// Names of the images containing a blob, as a whole file or a chunk
+    pub async fn find_images_with_hash(&self, hash: &str) -> Result<Vec<String>> {
+        let conn = self.db.lock().await;
+        let mut stmt = conn.prepare(
+            "SELECT DISTINCT images.name
+             FROM content JOIN images ON images.id = content.image_id
+             WHERE content.hash = ?1
+                OR content.hash IN (SELECT file_hash FROM chunks WHERE chunk_hash = ?1)
+             ORDER BY images.name",
+        )?;
+        let image_names = stmt
+            .query_map(params![hash], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+
+        Ok(image_names)
+    }
+
+    /// Entries of the directory at `path` in an image, with their attributes
+    pub async fn list_directory(
+        &self,
+        image_name: &str,
+        path: &str,
+    ) -> Result<Vec<(OsString, FileAttr)>> {
+        let conn = self.db.lock().await;
+        let image_id = image_id(&conn, image_name)?;
+
+        let mut ino = FUSE_ROOT_ID;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            ino = conn
+                .query_row(
+                    "SELECT ino FROM dir_entries
+                     WHERE image_id = ?1 AND parent = ?2 AND name = ?3",
+                    params![image_id, ino, name.as_bytes()],
+                    |row| row.get(0),
+                )
+                .optional()?
+                .with_context(|| format!("{} not found in {}", path, image_name))?;
+        }
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT dir_entries.name, {}
+             FROM dir_entries
+             JOIN inodes ON inodes.image_id = dir_entries.image_id AND inodes.ino = dir_entries.ino
+             WHERE dir_entries.image_id = ?1 AND dir_entries.parent = ?2
+             ORDER BY dir_entries.name",
+            INODE_COLUMNS
+        ))?;
+        let entries = stmt
+            .query_map(params![image_id, ino], |row| {
+                Ok((name_from_row(row, 0)?, attr_from_row(row, 1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(entries)
+    }
+
+    pub async fn get_image_data(&self, image_name: &str) -> Result<ImageTables> {
+        let conn = self.db.lock().await;
+        let image_id = image_id(&conn, image_name)?;
+        load_image(&conn, image_id)
+    }
+
+    /// Chunks of the chunked files of an image, by file hash, as
+    /// (hash, length) in file order
+    pub async fn get_image_chunks(
         &self,
         image_name: &str,
-    ) -> Result<(
-        HashMap<u64, HashMap<String, u64>>,
-        HashMap<u64, FileAttr>,
-        HashMap<u64, String>,
-    )> {
+    ) -> Result<HashMap<String, Vec<(String, u64)>>> {
         let conn = self.db.lock().await;
+        let image_id = image_id(&conn, image_name)?;
 
         let mut stmt = conn.prepare(
-            "SELECT directory, file_attr, inode_to_hash 
-             FROM indexer 
-             WHERE id = ?1",
+            "SELECT file_hash, chunk_hash, length FROM chunks
+             WHERE file_hash IN (SELECT hash FROM content WHERE image_id = ?1)
+             ORDER BY file_hash, idx",
         )?;
+        let mut rows = stmt.query(params![image_id])?;
+
+        let mut chunks: HashMap<String, Vec<(String, u64)>> = HashMap::new();
+        while let Some(row) = rows.next()? {
+            chunks
+                .entry(row.get(0)?)
+                .or_default()
+                .push((row.get(1)?, row.get(2)?));
+        }
+
+        Ok(chunks)
+    }
+
+    /// Extended attributes of the inodes of an image that have any
+    pub async fn get_image_xattrs(
+        &self,
+        image_name: &str,
+    ) -> Result<HashMap<u64, BTreeMap<String, Vec<u8>>>> {
+        let conn = self.db.lock().await;
+        let image_id = image_id(&conn, image_name)?;
+
+        let mut stmt = conn.prepare("SELECT ino, name, value FROM xattrs WHERE image_id = ?1")?;
+        let mut rows = stmt.query(params![image_id])?;
+
+        let mut xattrs: HashMap<u64, BTreeMap<String, Vec<u8>>> = HashMap::new();
+        while let Some(row) = rows.next()? {
+            xattrs
+                .entry(row.get(0)?)
+                .or_default()
+                .insert(row.get(1)?, row.get(2)?);
+        }
+
+        Ok(xattrs)
+    }
+}
+
+/// Schema migrations, applied in order. `PRAGMA user_version` records how
+/// many of them a database has been through.
+const MIGRATIONS: &[fn(&Transaction) -> Result<()>] = &[
+    create_initial_schema,
+    normalize_images,
+    renumber_inodes,
+    add_chunks,
+    add_xattrs,
+    store_names_as_bytes,
+];
+
+/// The original schema, storing each image as JSON maps in a single row
+fn create_initial_schema(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS indexer (
+            id TEXT PRIMARY KEY,
+            next_inode INTEGER,
+            directory TEXT,
+            file_attr TEXT,
+            inode_to_hash TEXT
+        );
+
+        CREATE TABLE IF NOT EXISTS next_inode (
+            id TEXT PRIMARY KEY,
+            next_inode INTEGER
+        );
+
+        CREATE TABLE IF NOT EXISTS traces (
+            id TEXT PRIMARY KEY,
+            inodes TEXT
+        );",
+    )?;
+
+    Ok(())
+}
+
+/// Split images into inode, directory entry and content reference tables,
+/// moving over the images indexed with the JSON schema
+fn normalize_images(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE images (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE
+        );
+
+        CREATE TABLE inodes (
+            image_id INTEGER NOT NULL REFERENCES images(id) ON DELETE CASCADE,
+            ino INTEGER NOT NULL,
+            kind TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            blocks INTEGER NOT NULL,
+            atime INTEGER NOT NULL,
+            mtime INTEGER NOT NULL,
+            ctime INTEGER NOT NULL,
+            crtime INTEGER NOT NULL,
+            perm INTEGER NOT NULL,
+            nlink INTEGER NOT NULL,
+            uid INTEGER NOT NULL,
+            gid INTEGER NOT NULL,
+            rdev INTEGER NOT NULL,
+            blksize INTEGER NOT NULL,
+            flags INTEGER NOT NULL,
+            PRIMARY KEY (image_id, ino)
+        );
+
+        CREATE TABLE dir_entries (
+            image_id INTEGER NOT NULL REFERENCES images(id) ON DELETE CASCADE,
+            parent INTEGER NOT NULL,
+            name TEXT NOT NULL,
+            ino INTEGER NOT NULL,
+            PRIMARY KEY (image_id, parent, name)
+        );
+
+        CREATE TABLE content (
+            image_id INTEGER NOT NULL REFERENCES images(id) ON DELETE CASCADE,
+            ino INTEGER NOT NULL,
+            hash TEXT NOT NULL,
+            PRIMARY KEY (image_id, ino)
+        );
+
+        CREATE INDEX content_hash ON content (hash);",
+    )?;
+
+    // Written against the tables as created above, not the current schema
+    let mut insert_image = tx.prepare("INSERT INTO images (name) VALUES (?1)")?;
+    let mut insert_inode = tx.prepare(
+        "INSERT INTO inodes (image_id, ino, kind, size, blocks, atime, mtime, ctime, crtime,
+                             perm, nlink, uid, gid, rdev, blksize, flags)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+    )?;
+    let mut insert_entry = tx
+        .prepare("INSERT INTO dir_entries (image_id, parent, name, ino) VALUES (?1, ?2, ?3, ?4)")?;
+    let mut insert_content =
+        tx.prepare("INSERT INTO content (image_id, ino, hash) VALUES (?1, ?2, ?3)")?;
+
+    let mut stmt = tx.prepare("SELECT id, directory, file_attr, inode_to_hash FROM indexer")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let image_name: String = row.get(0)?;
+        let directory: HashMap<u64, HashMap<String, u64>> =
+            serde_json::from_str(&row.get::<_, String>(1)?)?;
+        let file_attr: HashMap<u64, FileAttr> = serde_json::from_str(&row.get::<_, String>(2)?)?;
+        let inode_to_hash: HashMap<u64, String> = serde_json::from_str(&row.get::<_, String>(3)?)?;
+
+        info!("Migrating image {}", image_name);
+        insert_image.execute(params![image_name])?;
+        let image_id = tx.last_insert_rowid();
+
+        for attr in file_attr.values() {
+            insert_inode.execute(params![
+                image_id,
+                attr.ino,
+                kind_to_sql(attr.kind),
+                attr.size,
+                attr.blocks,
+                time_to_sql(attr.atime),
+                time_to_sql(attr.mtime),
+                time_to_sql(attr.ctime),
+                time_to_sql(attr.crtime),
+                attr.perm,
+                attr.nlink,
+                attr.uid,
+                attr.gid,
+                attr.rdev,
+                attr.blksize,
+                attr.flags,
+            ])?;
+        }
+        for (parent, children) in &directory {
+            for (name, ino) in children {
+                insert_entry.execute(params![image_id, parent, name, ino])?;
+            }
+        }
+        for (ino, hash) in &inode_to_hash {
+            insert_content.execute(params![image_id, ino, hash])?;
+        }
+    }
+    drop(rows);
+    drop(stmt);
+
+    tx.execute_batch("DROP TABLE indexer")?;
+
+    Ok(())
+}
+
+/// Give every image its own inode space rooted at `FUSE_ROOT_ID`, instead of
+/// a range of the global counter, numbered the way the indexer now does.
+/// Recorded traces refer to the old inodes and are dropped.
+fn renumber_inodes(tx: &Transaction) -> Result<()> {
+    let mut stmt = tx.prepare("SELECT id, name FROM images")?;
+    let images = stmt
+        .query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    drop(stmt);
+
+    tx.execute_batch(
+        "CREATE TEMP TABLE renumbered (
+            image_id INTEGER NOT NULL,
+            old_ino INTEGER NOT NULL,
+            new_ino INTEGER NOT NULL,
+            PRIMARY KEY (image_id, old_ino)
+        );",
+    )?;
+
+    let mut entries =
+        tx.prepare("SELECT parent, name, ino FROM dir_entries WHERE image_id = ?1")?;
+    let mut insert =
+        tx.prepare("INSERT INTO renumbered (image_id, old_ino, new_ino) VALUES (?1, ?2, ?3)")?;
+    for (image_id, image_name) in images {
+        let mut directory: HashMap<u64, HashMap<String, u64>> = HashMap::new();
+        let mut rows = entries.query(params![image_id])?;
+        while let Some(row) = rows.next()? {
+            directory
+                .entry(row.get(0)?)
+                .or_default()
+                .insert(row.get(1)?, row.get(2)?);
+        }
+
+        // The old root is the only entry under the reserved parent 0
+        let root = match directory.get(&0).and_then(|roots| roots.values().next()) {
+            Some(&root) => root,
+            None => anyhow::bail!("Image {} has no root directory", image_name),
+        };
+
+        info!("Renumbering inodes of image {}", image_name);
+        let mut next_inode = FUSE_ROOT_ID;
+        let mut stack = vec![root];
+        while let Some(ino) = stack.pop() {
+            insert.execute(params![image_id, ino, next_inode])?;
+            next_inode += 1;
+
+            if let Some(children) = directory.get(&ino) {
+                let mut names: Vec<_> = children.keys().collect();
+                // Reversed so the stack pops them in name order
+                names.sort_unstable_by(|a, b| b.cmp(a));
+                stack.extend(names.into_iter().map(|name| children[name]));
+            }
+        }
+    }
+    drop(entries);
+    drop(insert);
+
+    // Inodes are negated first so no renumbered row collides with one that
+    // still has its old number. Rows unreachable from the root are dropped.
+    tx.execute_batch(
+        "DELETE FROM inodes WHERE NOT EXISTS (SELECT 1 FROM renumbered AS r
+            WHERE r.image_id = inodes.image_id AND r.old_ino = inodes.ino);
+        UPDATE inodes SET ino = -ino;
+        UPDATE inodes SET ino = (SELECT new_ino FROM renumbered AS r
+            WHERE r.image_id = inodes.image_id AND r.old_ino = -inodes.ino);
+
+        DELETE FROM content WHERE NOT EXISTS (SELECT 1 FROM renumbered AS r
+            WHERE r.image_id = content.image_id AND r.old_ino = content.ino);
+        UPDATE content SET ino = -ino;
+        UPDATE content SET ino = (SELECT new_ino FROM renumbered AS r
+            WHERE r.image_id = content.image_id AND r.old_ino = -content.ino);
+
+        DELETE FROM dir_entries WHERE NOT EXISTS (SELECT 1 FROM renumbered AS r
+            WHERE r.image_id = dir_entries.image_id AND r.old_ino = dir_entries.parent);
+        UPDATE dir_entries SET parent = -parent, ino = -ino;
+        UPDATE dir_entries SET
+            parent = (SELECT new_ino FROM renumbered AS r
+                WHERE r.image_id = dir_entries.image_id AND r.old_ino = -dir_entries.parent),
+            ino = (SELECT new_ino FROM renumbered AS r
+                WHERE r.image_id = dir_entries.image_id AND r.old_ino = -dir_entries.ino);
+
+        DROP TABLE renumbered;
+        DROP TABLE next_inode;
+        DELETE FROM traces;",
+    )?;
+
+    Ok(())
+}
+
+/// Chunk lists of the files stored with content-defined chunking. They are
+/// keyed by file hash rather than image, since identical files always split
+/// into the same chunks.
+fn add_chunks(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE chunks (
+            file_hash TEXT NOT NULL,
+            idx INTEGER NOT NULL,
+            chunk_hash TEXT NOT NULL,
+            length INTEGER NOT NULL,
+            PRIMARY KEY (file_hash, idx)
+        );
+
+        CREATE INDEX chunks_chunk_hash ON chunks (chunk_hash);",
+    )?;
+
+    Ok(())
+}
+
+/// Extended attributes of inodes, such as file capabilities and SELinux
+/// labels
+fn add_xattrs(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE xattrs (
+            image_id INTEGER NOT NULL REFERENCES images(id) ON DELETE CASCADE,
+            ino INTEGER NOT NULL,
+            name TEXT NOT NULL,
+            value BLOB NOT NULL,
+            PRIMARY KEY (image_id, ino, name)
+        );",
+    )?;
+
+    Ok(())
+}
+
+/// Directory entry names as blobs of their raw bytes, since file names are
+/// not always valid UTF-8
+fn store_names_as_bytes(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE dir_entries_bytes (
+            image_id INTEGER NOT NULL REFERENCES images(id) ON DELETE CASCADE,
+            parent INTEGER NOT NULL,
+            name BLOB NOT NULL,
+            ino INTEGER NOT NULL,
+            PRIMARY KEY (image_id, parent, name)
+        );
+
+        INSERT INTO dir_entries_bytes (image_id, parent, name, ino)
+            SELECT image_id, parent, CAST(name AS BLOB), ino FROM dir_entries;
+
+        DROP TABLE dir_entries;
+        ALTER TABLE dir_entries_bytes RENAME TO dir_entries;",
+    )?;
+
+    Ok(())
+}
+
+/// Store an image, replacing any image previously indexed under that name
+fn insert_image(
+    tx: &Transaction,
+    image_name: &str,
+    directory: &HashMap<u64, HashMap<OsString, u64>>,
+    file_attr: &HashMap<u64, FileAttr>,
+    inode_to_hash: &HashMap<u64, String>,
+) -> Result<()> {
+    tx.execute("DELETE FROM images WHERE name = ?1", params![image_name])?;
+    tx.execute("INSERT INTO images (name) VALUES (?1)", params![image_name])?;
+    let image_id = tx.last_insert_rowid();
+
+    let mut stmt = tx.prepare(
+        "INSERT INTO inodes (image_id, ino, kind, size, blocks, atime, mtime, ctime, crtime,
+                             perm, nlink, uid, gid, rdev, blksize, flags)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+    )?;
+    for attr in file_attr.values() {
+        stmt.execute(params![
+            image_id,
+            attr.ino,
+            kind_to_sql(attr.kind),
+            attr.size,
+            attr.blocks,
+            time_to_sql(attr.atime),
+            time_to_sql(attr.mtime),
+            time_to_sql(attr.ctime),
+            time_to_sql(attr.crtime),
+            attr.perm,
+            attr.nlink,
+            attr.uid,
+            attr.gid,
+            attr.rdev,
+            attr.blksize,
+            attr.flags,
+        ])?;
+    }
+
+    let mut stmt = tx
+        .prepare("INSERT INTO dir_entries (image_id, parent, name, ino) VALUES (?1, ?2, ?3, ?4)")?;
+    for (parent, children) in directory {
+        for (name, ino) in children {
+            stmt.execute(params![image_id, parent, name.as_bytes(), ino])?;
+        }
+    }
+
+    let mut stmt = tx.prepare("INSERT INTO content (image_id, ino, hash) VALUES (?1, ?2, ?3)")?;
+    for (ino, hash) in inode_to_hash {
+        stmt.execute(params![image_id, ino, hash])?;
+    }
+
+    Ok(())
+}
+
+/// Store the chunk lists of files, keeping the existing list of a file that
+/// was already chunked by another image
+fn insert_chunks(
+    tx: &Transaction,
+    file_chunks: &HashMap<String, Vec<(String, u64)>>,
+) -> Result<()> {
+    let mut exists = tx.prepare("SELECT EXISTS (SELECT 1 FROM chunks WHERE file_hash = ?1)")?;
+    let mut insert = tx.prepare(
+        "INSERT INTO chunks (file_hash, idx, chunk_hash, length) VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    for (file_hash, chunks) in file_chunks {
+        if exists.query_row(params![file_hash], |row| row.get::<_, bool>(0))? {
+            continue;
+        }
+
+        for (idx, (chunk_hash, length)) in chunks.iter().enumerate() {
+            insert.execute(params![file_hash, idx, chunk_hash, length])?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Store the extended attributes of an image saved by `insert_image`
+fn insert_xattrs(
+    tx: &Transaction,
+    image_name: &str,
+    xattrs: &HashMap<u64, BTreeMap<String, Vec<u8>>>,
+) -> Result<()> {
+    let image_id = image_id(tx, image_name)?;
+
+    let mut stmt =
+        tx.prepare("INSERT INTO xattrs (image_id, ino, name, value) VALUES (?1, ?2, ?3, ?4)")?;
+    for (ino, attrs) in xattrs {
+        for (name, value) in attrs {
+            stmt.execute(params![image_id, ino, name, value])?;
+        }
+    }
+
+    Ok(())
+}
+
+fn load_image(conn: &Connection, image_id: i64) -> Result<ImageTables> {
+    let mut directory: HashMap<u64, HashMap<OsString, u64>> = HashMap::new();
+    let mut stmt = conn.prepare("SELECT parent, name, ino FROM dir_entries WHERE image_id = ?1")?;
+    let mut rows = stmt.query(params![image_id])?;
+    while let Some(row) = rows.next()? {
+        directory
+            .entry(row.get(0)?)
+            .or_default()
+            .insert(name_from_row(row, 1)?, row.get(2)?);
+    }
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM inodes WHERE image_id = ?1",
+        INODE_COLUMNS
+    ))?;
+    let file_attr = stmt
+        .query_map(params![image_id], |row| {
+            let attr = attr_from_row(row, 0)?;
+            Ok((attr.ino, attr))
+        })?
+        .collect::<Result<HashMap<_, _>, _>>()?;
+
+    let mut stmt = conn.prepare("SELECT ino, hash FROM content WHERE image_id = ?1")?;
+    let inode_to_hash = stmt
+        .query_map(params![image_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<HashMap<_, _>, _>>()?;
+
+    Ok((directory, file_attr, inode_to_hash))
+}
+
+fn image_id(conn: &Connection, image_name: &str) -> Result<i64> {
+    conn.query_row(
+        "SELECT id FROM images WHERE name = ?1",
+        params![image_name],
+        |row| row.get(0),
+    )
+    .optional()?
+    .with_context(|| format!("Image {} is not indexed", image_name))
+}
+
+/// Columns of the `inodes` table read back by `attr_from_row`
+const INODE_COLUMNS: &str = "inodes.ino, inodes.kind, inodes.size, inodes.blocks, inodes.atime,
+    inodes.mtime, inodes.ctime, inodes.crtime, inodes.perm, inodes.nlink, inodes.uid, inodes.gid,
+    inodes.rdev, inodes.blksize, inodes.flags";
+
+fn attr_from_row(row: &Row, start: usize) -> rusqlite::Result<FileAttr> {
+    let kind: String = row.get(start + 1)?;
+
+    Ok(FileAttr {
+        ino: row.get(start)?,
+        kind: kind_from_sql(&kind).ok_or_else(|| {
+            rusqlite::Error::FromSqlConversionFailure(
+                start + 1,
+                rusqlite::types::Type::Text,
+                format!("Unknown file type {}", kind).into(),
+            )
+        })?,
+        size: row.get(start + 2)?,
+        blocks: row.get(start + 3)?,
+        atime: time_from_sql(row.get(start + 4)?),
+        mtime: time_from_sql(row.get(start + 5)?),
+        ctime: time_from_sql(row.get(start + 6)?),
+        crtime: time_from_sql(row.get(start + 7)?),
+        perm: row.get(start + 8)?,
+        nlink: row.get(start + 9)?,
+        uid: row.get(start + 10)?,
+        gid: row.get(start + 11)?,
+        rdev: row.get(start + 12)?,
+        blksize: row.get(start + 13)?,
+        flags: row.get(start + 14)?,
+    })
+}
+
+/// Directory entry names are blobs of their raw bytes, text is read as its
+/// UTF-8 bytes
+fn name_from_row(row: &Row, idx: usize) -> rusqlite::Result<OsString> {
+    match row.get_ref(idx)? {
+        ValueRef::Blob(name) | ValueRef::Text(name) => Ok(OsString::from_vec(name.to_vec())),
+        other => Err(rusqlite::Error::FromSqlConversionFailure(
+            idx,
+            other.data_type(),
+            "Directory entry name is neither text nor a blob".into(),
+        )),
+    }
+}
+
+fn kind_to_sql(kind: FileType) -> &'static str {
+    match kind {
+        FileType::NamedPipe => "fifo",
+        FileType::CharDevice => "char",
+        FileType::BlockDevice => "block",
+        FileType::Directory => "dir",
+        FileType::RegularFile => "file",
+        FileType::Symlink => "symlink",
+        FileType::Socket => "socket",
+    }
+}
+
+fn kind_from_sql(kind: &str) -> Option<FileType> {
+    match kind {
+        "fifo" => Some(FileType::NamedPipe),
+        "char" => Some(FileType::CharDevice),
+        "block" => Some(FileType::BlockDevice),
+        "dir" => Some(FileType::Directory),
+        "file" => Some(FileType::RegularFile),
+        "symlink" => Some(FileType::Symlink),
+        "socket" => Some(FileType::Socket),
+        _ => None,
+    }
+}
+
+/// Timestamps are stored as nanoseconds relative to the Unix epoch
+fn time_to_sql(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => after.as_nanos() as i64,
+        Err(e) => -(e.duration().as_nanos() as i64),
+    }
+}
+
+fn time_from_sql(nanos: i64) -> SystemTime {
+    if nanos >= 0 {
+        UNIX_EPOCH + Duration::from_nanos(nanos as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_nanos(nanos.unsigned_abs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    fn temp_db(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("silo-database-{}-{}.db", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn attr(ino: u64, kind: FileType) -> FileAttr {
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        FileAttr {
+            ino,
+            size: 0,
+            blocks: 0,
+            atime: time,
+            mtime: time,
+            ctime: time,
+            crtime: time,
+            kind,
+            perm: 0o755,
+            nlink: 1,
+            uid: 1000,
+            gid: 1000,
+            rdev: 0,
+            flags: 0,
+            blksize: 4096,
+        }
+    }
+
+    /// Store an image the way indexers before the migrations did: as JSON
+    /// maps in a single row, numbered from a counter shared by all images
+    /// and with the root under parent 0
+    fn create_baseline_db(path: &Path) {
+        let conn = Connection::open(path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE indexer (
+                id TEXT PRIMARY KEY,
+                next_inode INTEGER,
+                directory TEXT,
+                file_attr TEXT,
+                inode_to_hash TEXT
+            );
+
+            CREATE TABLE next_inode (
+                id TEXT PRIMARY KEY,
+                next_inode INTEGER
+            );
+
+            INSERT INTO next_inode (id, next_inode) VALUES ('next_inode', 14);",
+        )
+        .unwrap();
+
+        let directory: HashMap<u64, HashMap<String, u64>> = HashMap::from([
+            (0, HashMap::from([(String::from("rootfs"), 10)])),
+            (
+                10,
+                HashMap::from([(String::from("b"), 12), (String::from("a"), 11)]),
+            ),
+            (11, HashMap::from([(String::from("x"), 13)])),
+        ]);
+        let file_attr: HashMap<u64, FileAttr> = HashMap::from([
+            (10, attr(10, FileType::Directory)),
+            (11, attr(11, FileType::Directory)),
+            (12, attr(12, FileType::RegularFile)),
+            (13, attr(13, FileType::RegularFile)),
+        ]);
+        let inode_to_hash: HashMap<u64, String> =
+            HashMap::from([(12, String::from("hash-b")), (13, String::from("hash-x"))]);
+
+        conn.execute(
+            "INSERT INTO indexer (id, next_inode, directory, file_attr, inode_to_hash)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                "old:latest",
+                14,
+                serde_json::to_string(&directory).unwrap(),
+                serde_json::to_string(&file_attr).unwrap(),
+                serde_json::to_string(&inode_to_hash).unwrap()
+            ],
+        )
+        .unwrap();
+    }
+
+    async fn open(path: &Path) -> Result<AppState> {
+        AppState::new(
+            path.to_string_lossy().into_owned(),
+            std::env::temp_dir(),
+            false,
+            false,
+        )
+        .await
+    }
+
+    async fn user_version(state: &AppState) -> usize {
+        let conn = state.db.lock().await;
+        conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn migrates_baseline_database() {
+        let path = temp_db("baseline");
+        create_baseline_db(&path);
+
+        let state = open(&path).await.unwrap();
+        assert_eq!(user_version(&state).await, MIGRATIONS.len());
+        assert_eq!(state.get_indexed_images().await.unwrap(), ["old:latest"]);
+
+        // Renumbered from FUSE_ROOT_ID, depth-first in name order
+        let (directory, file_attr, inode_to_hash) =
+            state.get_image_data("old:latest").await.unwrap();
+        assert_eq!(
+            directory,
+            HashMap::from([
+                (
+                    FUSE_ROOT_ID,
+                    HashMap::from([(OsString::from("a"), 2), (OsString::from("b"), 4)])
+                ),
+                (2, HashMap::from([(OsString::from("x"), 3)])),
+            ])
+        );
+        let mut inodes: Vec<_> = file_attr
+            .iter()
+            .map(|(ino, attr)| (*ino, attr.ino))
+            .collect();
+        inodes.sort_unstable();
+        assert_eq!(inodes, [(1, 1), (2, 2), (3, 3), (4, 4)]);
+        assert_eq!(file_attr[&4].kind, FileType::RegularFile);
+        assert_eq!(file_attr[&4].mtime, attr(12, FileType::RegularFile).mtime);
+        assert_eq!(
+            inode_to_hash,
+            HashMap::from([(4, String::from("hash-b")), (3, String::from("hash-x"))])
+        );
+
+        let entries = state.list_directory("old:latest", "/a").await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].0, "x");
+
+        {
+            let conn = state.db.lock().await;
+            // Tables of the JSON schema are gone, the later ones are there
+            let tables: Vec<String> = conn
+                .prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+                .unwrap()
+                .query_map([], |row| row.get(0))
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(
+                tables,
+                [
+                    "chunks",
+                    "content",
+                    "dir_entries",
+                    "images",
+                    "inodes",
+                    "traces",
+                    "xattrs"
+                ]
+            );
+
+            let text_names: i64 = conn
+                .query_row(
+                    "SELECT COUNT(*) FROM dir_entries WHERE typeof(name) != 'blob'",
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(text_names, 0);
+        }
+
+        // Reopening applies nothing more
+        drop(state);
+        let state = open(&path).await.unwrap();
+        assert_eq!(user_version(&state).await, MIGRATIONS.len());
+        assert!(state.get_image_data("old:latest").await.is_ok());
+
+        drop(state);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn migrates_text_names_to_bytes() {
+        let path = temp_db("text-names");
+        {
+            let mut conn = Connection::open(&path).unwrap();
+            let tx = conn.transaction().unwrap();
+            for migration in &MIGRATIONS[..MIGRATIONS.len() - 1] {
+                migration(&tx).unwrap();
+            }
+            tx.pragma_update(None, "user_version", MIGRATIONS.len() - 1)
+                .unwrap();
+            tx.execute_batch(
+                "INSERT INTO images (id, name) VALUES (1, 'text:latest');
+                 INSERT INTO dir_entries (image_id, parent, name, ino) VALUES (1, 1, 'é', 2);",
+            )
+            .unwrap();
+            tx.commit().unwrap();
+        }
+
+        let state = open(&path).await.unwrap();
+        assert_eq!(user_version(&state).await, MIGRATIONS.len());
+        let (directory, _, _) = state.get_image_data("text:latest").await.unwrap();
+        assert_eq!(directory[&1][&OsString::from("é")], 2);
+
+        drop(state);
+        fs::remove_file(&path).unwrap();
+    }
 
-        let (directory, file_attr, inode_to_hash) = stmt.query_row(params![image_name], |row| {
-            Ok((
-                row.get::<_, String>(0)?,
-                row.get::<_, String>(1)?,
-                row.get::<_, String>(2)?,
-            ))
-        })?;
+    #[tokio::test]
+    async fn rejects_newer_schema() {
+        let path = temp_db("newer");
+        Connection::open(&path)
+            .unwrap()
+            .pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
 
-        let directory: HashMap<u64, HashMap<String, u64>> = serde_json::from_str(&directory)?;
-        let file_attr: HashMap<u64, FileAttr> = serde_json::from_str(&file_attr)?;
-        let inode_to_hash: HashMap<u64, String> = serde_json::from_str(&inode_to_hash)?;
+        assert!(open(&path).await.is_err());
 
-        Ok((directory, file_attr, inode_to_hash))
+        fs::remove_file(&path).unwrap();
     }
 }
@@ -1,75 +1,194 @@
-use std::collections::HashMap;
+use log::{debug, info, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{CString, OsString};
 use std::fs::{self, File};
-use std::io::{self, Write};
-use std::os::unix::fs::MetadataExt;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
-use std::time::UNIX_EPOCH;
-use log::{debug};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
-use fuser::{FileAttr, FileType};
+use fuser::{FileAttr, FileType, FUSE_ROOT_ID};
 use sha2::{Digest, Sha256};
 
+use crate::blobs::{self, COMPRESSION_LEVEL, MAX_COMPRESSED_SIZE};
+use crate::chunker::{Chunker, MAX_CHUNK_SIZE};
+
+/// Size of the reads files are hashed and copied with
+const READ_BUF_SIZE: usize = 256 * 1024;
+
+/// Interval between two progress reports
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+
+const MIB: f64 = 1024.0 * 1024.0;
+
+/// A regular file found while walking the tree, whose content is hashed
+/// once the walk is done
+struct PendingFile {
+    ino: u64,
+    path: PathBuf,
+    size: u64,
+}
+
+/// Metadata of an entry that could not be reproduced on disk when its image
+/// was unpacked, taking precedence over what is found on disk
+pub struct RecordedMetadata {
+    pub uid: u32,
+    pub gid: u32,
+    /// Kind and device number of a device node unpacked as an empty file
+    pub device: Option<(FileType, u32)>,
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+}
+
 pub struct ContentIndexer {
     pub image_name: String,
-    pub last_saved_inode: u64,
     pub next_inode: u64,
-    pub directory: HashMap<u64, HashMap<String, u64>>,
+    /// Entries of every directory, by name as raw bytes on disk
+    pub directory: HashMap<u64, HashMap<OsString, u64>>,
     pub file_attr: HashMap<u64, FileAttr>,
     pub inode_to_hash: HashMap<u64, String>,
+    /// Extended attributes of the inodes that have any
+    pub xattrs: HashMap<u64, BTreeMap<String, Vec<u8>>>,
+    /// Chunks of the files stored in chunks, by file hash, as (hash, length)
+    pub file_chunks: HashMap<String, Vec<(String, u64)>>,
     pub output_folder: PathBuf,
     pub total_files: usize,
+    pub total_bytes: u64,
     pub processed_files: Arc<AtomicUsize>,
+    pub processed_bytes: Arc<AtomicU64>,
+    /// Number of threads hashing files at once
+    pub workers: usize,
+    /// Store files larger than a chunk as content-defined chunks instead of
+    /// whole blobs, so files that are mostly identical share storage
+    pub chunking: bool,
+    /// Store blobs compressed with zstd. Sizes in `file_attr` stay the
+    /// uncompressed sizes.
+    pub compression: bool,
+    /// Metadata recorded while unpacking, by path relative to the root
+    pub recorded: HashMap<PathBuf, RecordedMetadata>,
+    root: PathBuf,
+    /// Inode given to each file with several links, by (device, inode) on disk
+    hardlinks: HashMap<(u64, u64), u64>,
+    pending: Vec<PendingFile>,
 }
 
 impl ContentIndexer {
-    pub fn new(image_name: &str, last_saved_inode: u64, output_folder: PathBuf) -> Self {
+    pub fn new(image_name: &str, output_folder: PathBuf) -> Self {
         Self {
             image_name: image_name.to_string(),
-            last_saved_inode,
-            next_inode: last_saved_inode,
+            next_inode: FUSE_ROOT_ID,
             directory: HashMap::new(),
             file_attr: HashMap::new(),
             inode_to_hash: HashMap::new(),
+            xattrs: HashMap::new(),
+            file_chunks: HashMap::new(),
             output_folder,
             total_files: 0,
+            total_bytes: 0,
             processed_files: Arc::new(AtomicUsize::new(0)),
+            processed_bytes: Arc::new(AtomicU64::new(0)),
+            workers: thread::available_parallelism().map_or(4, |n| n.get()),
+            chunking: false,
+            compression: false,
+            recorded: HashMap::new(),
+            root: PathBuf::new(),
+            hardlinks: HashMap::new(),
+            pending: Vec::new(),
         }
     }
 
-    pub fn save_directory(&mut self, path: &Path, parent_ino: u64) -> io::Result<u64> {
-        let ino = if self.last_saved_inode == self.next_inode {
-            1
-        } else {
-            self.next_inode
-        };
+    /// Index `path` and everything below it. Every image has its own inode
+    /// space: the first path saved becomes `FUSE_ROOT_ID` and the rest are
+    /// numbered depth-first in name order, so indexing the same tree twice
+    /// gives the same inodes.
+    ///
+    /// The tree is walked on the calling thread, then the regular files are
+    /// hashed and stored by a pool of `workers` threads.
+    pub fn save_directory(&mut self, path: &Path) -> io::Result<u64> {
+        self.root = path.to_path_buf();
+        let root = self.walk(path)?;
 
-        self.next_inode += 1;
+        let pending = std::mem::take(&mut self.pending);
+        self.total_files = pending.len();
+        self.total_bytes = pending.iter().map(|file| file.size).sum();
+        info!(
+            "Hashing {} files ({} bytes) with {} workers",
+            self.total_files, self.total_bytes, self.workers
+        );
+
+        self.hash_files(pending)?;
+
+        Ok(root)
+    }
 
+    /// Assign inodes and record attributes and directory entries, leaving
+    /// regular files in `pending`. Every link to a file shares its inode.
+    ///
+    /// Runs on one thread so inodes come out in a fixed order: it only reads
+    /// metadata, and the file contents are read by `hash_files`.
+    fn walk(&mut self, path: &Path) -> io::Result<u64> {
         let metadata = fs::symlink_metadata(path)?;
-        let file_type = if metadata.is_dir() {
-            FileType::Directory
-        } else if metadata.is_symlink() {
-            FileType::Symlink
-        } else {
-            FileType::RegularFile
+
+        let link_key = (metadata.dev(), metadata.ino());
+        let linked = !metadata.is_dir() && metadata.nlink() > 1;
+        if linked {
+            if let Some(&ino) = self.hardlinks.get(&link_key) {
+                if let Some(attr) = self.file_attr.get_mut(&ino) {
+                    attr.nlink += 1;
+                }
+                return Ok(ino);
+            }
+        }
+
+        let ino = self.next_inode;
+        self.next_inode += 1;
+        if linked {
+            self.hardlinks.insert(link_key, ino);
+        }
+
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        let recorded = self.recorded.get(relative);
+
+        let (file_type, rdev) = match recorded.and_then(|recorded| recorded.device) {
+            Some(device) => device,
+            None => (file_type_of(&metadata), metadata.rdev() as u32),
+        };
+        let (uid, gid) = match recorded {
+            Some(recorded) => (recorded.uid, recorded.gid),
+            None => (metadata.uid(), metadata.gid()),
         };
 
+        let xattrs = match recorded {
+            Some(recorded) => recorded.xattrs.clone(),
+            None => read_xattrs(path)?,
+        };
+        if !xattrs.is_empty() {
+            self.xattrs.insert(ino, xattrs);
+        }
+
+        // Only the mtime survives in image layers, the other timestamps depend
+        // on when the image was unpacked or indexed
+        let mtime = metadata.modified().unwrap_or(UNIX_EPOCH);
+
         let attr = FileAttr {
             ino,
             size: metadata.len(),
             blocks: metadata.blocks(),
-            atime: metadata.accessed().unwrap_or(UNIX_EPOCH),
-            mtime: metadata.modified().unwrap_or(UNIX_EPOCH),
-            ctime: metadata.created().unwrap_or(UNIX_EPOCH),
-            crtime: metadata.created().unwrap_or(UNIX_EPOCH),
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+            crtime: mtime,
             kind: file_type,
-            perm: metadata.mode() as u16,
-            nlink: metadata.nlink() as u32,
-            uid: 1000,
-            gid: 1000,
-            rdev: metadata.rdev() as u32,
+            perm: (metadata.mode() & 0o7777) as u16,
+            // Counted within the image, links from outside it do not show
+            nlink: 1,
+            uid,
+            gid,
+            rdev,
             flags: 0,
             blksize: 4096,
         };
@@ -78,58 +197,381 @@ impl ContentIndexer {
 
         match file_type {
             FileType::Directory => {
+                let mut entries = fs::read_dir(path)?.collect::<io::Result<Vec<_>>>()?;
+                entries.sort_by_key(|entry| entry.file_name());
+
                 let mut children = HashMap::new();
-                for entry in fs::read_dir(path)? {
-                    let entry = entry?;
-                    let file_name = entry.file_name().into_string().unwrap();
-                    let child_ino = self.save_directory(&entry.path(), ino)?;
+                let mut subdirs = 0;
+                for entry in entries {
+                    let file_name = entry.file_name();
+                    let child_ino = self.walk(&entry.path())?;
+                    if entry.file_type()?.is_dir() {
+                        subdirs += 1;
+                    }
                     children.insert(file_name, child_ino);
                 }
                 self.directory.insert(ino, children);
+
+                // `.`, the entry in the parent and `..` of every subdirectory
+                if let Some(attr) = self.file_attr.get_mut(&ino) {
+                    attr.nlink = 2 + subdirs;
+                }
             }
             FileType::Symlink => {
                 let target = fs::read_link(path)?;
-                let content = target.to_string_lossy().into_owned();
-                self.save_content(ino, content.as_bytes())?;
+                self.save_content(ino, target.as_os_str().as_bytes())?;
             }
             FileType::RegularFile => {
-                let content = fs::read(path)?;
-                self.save_content(ino, &content)?;
+                self.pending.push(PendingFile {
+                    ino,
+                    path: path.to_path_buf(),
+                    size: metadata.len(),
+                });
             }
             _ => {}
         }
 
-        if parent_ino != ino {
-            self.directory
-                .entry(parent_ino)
-                .or_insert_with(HashMap::new)
-                .insert(path.file_name().unwrap().to_str().unwrap().to_string(), ino);
+        Ok(ino)
+    }
+
+    /// Hash and store files on a bounded pool of threads, reporting progress
+    /// while they run
+    fn hash_files(&mut self, pending: Vec<PendingFile>) -> io::Result<()> {
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let hashes = Mutex::new(HashMap::with_capacity(pending.len()));
+        let file_chunks = Mutex::new(HashMap::new());
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+
+        let this = &*self;
+        let (pending, next, failed, hashes, file_chunks) =
+            (&pending, &next, &failed, &hashes, &file_chunks);
+        let result = thread::scope(|scope| {
+            scope.spawn(move || this.report_progress(done_rx));
+
+            let workers: Vec<_> = (0..this.workers.max(1))
+                .map(|_| {
+                    scope.spawn(move || -> io::Result<()> {
+                        while !failed.load(Ordering::Relaxed) {
+                            let file = match pending.get(next.fetch_add(1, Ordering::Relaxed)) {
+                                Some(file) => file,
+                                None => break,
+                            };
+
+                            let chunked = this.chunking && file.size > MAX_CHUNK_SIZE as u64;
+                            let stored = if chunked {
+                                this.store_chunks(&file.path).map(|(hash, chunks)| {
+                                    file_chunks.lock().unwrap().insert(hash.clone(), chunks);
+                                    hash
+                                })
+                            } else {
+                                this.store_file(&file.path, file.size)
+                            };
+                            let hash = stored.inspect_err(|_| {
+                                failed.store(true, Ordering::Relaxed);
+                            })?;
+                            hashes.lock().unwrap().insert(file.ino, hash);
+
+                            let processed =
+                                this.processed_files.fetch_add(1, Ordering::Relaxed) + 1;
+                            debug!(
+                                "Progress: {}/{} files processed",
+                                processed, this.total_files
+                            );
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+
+            let result = workers
+                .into_iter()
+                .try_for_each(|worker| worker.join().unwrap());
+
+            // Stops the progress reporter
+            drop(done_tx);
+            result
+        });
+
+        self.inode_to_hash.extend(hashes.lock().unwrap().drain());
+        self.file_chunks.extend(file_chunks.lock().unwrap().drain());
+        result
+    }
+
+    /// Log files and bytes processed, throughput and remaining time until
+    /// `done` is dropped
+    fn report_progress(&self, done: mpsc::Receiver<()>) {
+        let start = Instant::now();
+
+        while let Err(RecvTimeoutError::Timeout) = done.recv_timeout(PROGRESS_INTERVAL) {
+            let files = self.processed_files.load(Ordering::Relaxed);
+            let bytes = self.processed_bytes.load(Ordering::Relaxed);
+            let elapsed = start.elapsed().as_secs_f64();
+            let throughput = bytes as f64 / elapsed;
+
+            let eta = if throughput > 0.0 {
+                format!(
+                    "{:.0}s",
+                    self.total_bytes.saturating_sub(bytes) as f64 / throughput
+                )
+            } else {
+                "unknown".to_string()
+            };
+
+            info!(
+                "Progress: {}/{} files, {:.1}/{:.1} MiB ({:.1}%), {:.1} MiB/s, ETA {}",
+                files,
+                self.total_files,
+                bytes as f64 / MIB,
+                self.total_bytes as f64 / MIB,
+                bytes as f64 * 100.0 / self.total_bytes.max(1) as f64,
+                throughput / MIB,
+                eta
+            );
         }
+    }
 
-        let processed = self.processed_files.fetch_add(1, Ordering::SeqCst) + 1;
-        debug!(
-            "Progress: {}/{} files processed",
-            processed, self.total_files
-        );
+    /// Hash a file without loading it whole, then copy it into the content
+    /// folder unless a blob with that hash is already stored
+    fn store_file(&self, path: &Path, size: u64) -> io::Result<String> {
+        let hash = self.hash_file(path)?;
 
-        Ok(ino)
+        if !blobs::exists(&self.output_folder, &hash) {
+            self.write_blob(&hash, size, |tmp| {
+                io::copy(&mut File::open(path)?, tmp)?;
+                Ok(())
+            })?;
+        }
+
+        Ok(hash)
+    }
+
+    /// Store the content of `path` again, to repair the blob `damaged` of
+    /// the file stored under `hash`: the file itself or, if the file is
+    /// stored in chunks, one of them. Nothing is written and `false` is
+    /// returned if the content of `path` no longer hashes to `hash`.
+    pub fn restore(
+        &self,
+        path: &Path,
+        hash: &str,
+        damaged: &str,
+        chunked: bool,
+    ) -> io::Result<bool> {
+        let metadata = fs::symlink_metadata(path)?;
+
+        if metadata.is_symlink() {
+            let target = fs::read_link(path)?;
+            let content = target.as_os_str().as_bytes();
+            if self.hash_content(content) != hash {
+                return Ok(false);
+            }
+
+            blobs::remove(&self.output_folder, damaged)?;
+            self.write_blob(hash, content.len() as u64, |tmp| tmp.write_all(content))?;
+            return Ok(true);
+        }
+
+        if self.hash_file(path)? != hash {
+            return Ok(false);
+        }
+
+        // Blobs are only written when missing, so the damaged one goes first
+        blobs::remove(&self.output_folder, damaged)?;
+        if chunked {
+            self.store_chunks(path)?;
+        } else {
+            self.write_blob(hash, metadata.len(), |tmp| {
+                io::copy(&mut File::open(path)?, tmp)?;
+                Ok(())
+            })?;
+        }
+        Ok(true)
+    }
+
+    /// Hash a file without loading it whole
+    fn hash_file(&self, path: &Path) -> io::Result<String> {
+        let mut file = File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; READ_BUF_SIZE];
+
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            self.processed_bytes.fetch_add(n as u64, Ordering::Relaxed);
+        }
+
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// Split a file into content-defined chunks and store each chunk as a
+    /// blob, returning the hash of the whole file and its chunks
+    fn store_chunks(&self, path: &Path) -> io::Result<(String, Vec<(String, u64)>)> {
+        let mut hasher = Sha256::new();
+        let mut chunks = Vec::new();
+
+        for chunk in Chunker::new(File::open(path)?) {
+            let chunk = chunk?;
+            hasher.update(&chunk);
+            self.processed_bytes
+                .fetch_add(chunk.len() as u64, Ordering::Relaxed);
+
+            let chunk_hash = self.hash_content(&chunk);
+            if !blobs::exists(&self.output_folder, &chunk_hash) {
+                self.write_blob(&chunk_hash, chunk.len() as u64, |tmp| tmp.write_all(&chunk))?;
+            }
+            chunks.push((chunk_hash, chunk.len() as u64));
+        }
+
+        Ok((format!("{:x}", hasher.finalize()), chunks))
     }
 
     fn save_content(&mut self, ino: u64, content: &[u8]) -> io::Result<()> {
         let hash = self.hash_content(content);
-        let file_path = self.output_folder.join(&hash);
 
-        if !file_path.exists() {
-            File::create(file_path)?.write_all(content)?;
+        if !blobs::exists(&self.output_folder, &hash) {
+            self.write_blob(&hash, content.len() as u64, |tmp| tmp.write_all(content))?;
         }
 
         self.inode_to_hash.insert(ino, hash);
         Ok(())
     }
 
+    /// Write a blob of `size` bytes under a temporary name and move it in
+    /// place, so readers and concurrent writers of the same blob never see it
+    /// partially written. The blob is compressed on the way if compression is
+    /// enabled and it is small enough.
+    fn write_blob(
+        &self,
+        hash: &str,
+        size: u64,
+        write: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let tmp_path = self
+            .output_folder
+            .join(format!("tmp-{}-{}", hash, rand::random::<u32>()));
+
+        let compress = self.compression && size <= MAX_COMPRESSED_SIZE;
+        let path = if compress {
+            blobs::compressed_path(&self.output_folder, hash)
+        } else {
+            blobs::raw_path(&self.output_folder, hash)
+        };
+
+        let result = File::create(&tmp_path)
+            .and_then(|mut tmp| {
+                if compress {
+                    let mut encoder = zstd::Encoder::new(tmp, COMPRESSION_LEVEL)?;
+                    // Records the size in the frame header, which the server
+                    // reads to tell whether a range covers the whole blob
+                    encoder.set_pledged_src_size(Some(size))?;
+                    write(&mut encoder)?;
+                    encoder.finish()?;
+                    Ok(())
+                } else {
+                    write(&mut tmp)
+                }
+            })
+            .and_then(|()| fs::rename(&tmp_path, path));
+
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        result
+    }
+
     fn hash_content<T: AsRef<[u8]>>(&self, content: T) -> String {
         let mut hasher = Sha256::new();
         hasher.update(content);
         format!("{:x}", hasher.finalize())
     }
-}
\ No newline at end of file
+}
+
+fn file_type_of(metadata: &fs::Metadata) -> FileType {
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        FileType::Directory
+    } else if file_type.is_symlink() {
+        FileType::Symlink
+    } else if file_type.is_char_device() {
+        FileType::CharDevice
+    } else if file_type.is_block_device() {
+        FileType::BlockDevice
+    } else if file_type.is_fifo() {
+        FileType::NamedPipe
+    } else if file_type.is_socket() {
+        FileType::Socket
+    } else {
+        FileType::RegularFile
+    }
+}
+
+/// Extended attributes of a path, without following symlinks
+fn read_xattrs(path: &Path) -> io::Result<BTreeMap<String, Vec<u8>>> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut xattrs = BTreeMap::new();
+
+    let names = match xattr_call(|buf, len| unsafe {
+        libc::llistxattr(c_path.as_ptr(), buf.cast(), len)
+    }) {
+        Ok(names) => names,
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(xattrs),
+        Err(e) => return Err(e),
+    };
+
+    for name in names
+        .split(|&byte| byte == 0)
+        .filter(|name| !name.is_empty())
+    {
+        let c_name = CString::new(name)?;
+        let value = match xattr_call(|buf, len| unsafe {
+            libc::lgetxattr(c_path.as_ptr(), c_name.as_ptr(), buf.cast(), len)
+        }) {
+            Ok(value) => value,
+            // Removed since it was listed
+            Err(e) if e.raw_os_error() == Some(libc::ENODATA) => continue,
+            Err(e) => return Err(e),
+        };
+
+        match String::from_utf8(name.to_vec()) {
+            Ok(name) => {
+                xattrs.insert(name, value);
+            }
+            Err(_) => warn!(
+                "Skipping extended attribute of {} with a non UTF-8 name",
+                path.display()
+            ),
+        }
+    }
+
+    Ok(xattrs)
+}
+
+/// Run an xattr call that reports the size it needs when given an empty
+/// buffer, retrying if the value grows between the two calls
+fn xattr_call(call: impl Fn(*mut u8, usize) -> isize) -> io::Result<Vec<u8>> {
+    loop {
+        let size = call(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0u8; size as usize];
+        if buf.is_empty() {
+            return Ok(buf);
+        }
+
+        let read = call(buf.as_mut_ptr(), buf.len());
+        if read >= 0 {
+            buf.truncate(read as usize);
+            return Ok(buf);
+        }
+
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::ERANGE) {
+            return Err(e);
+        }
+    }
+}
@@ -1,14 +1,24 @@
 use anyhow::Result;
 use clap::Parser;
 use log::{error, info};
+use std::path::Path;
 use tokio::io::{self, AsyncBufReadExt};
 mod args;
+mod blobs;
+mod chunker;
 mod commands;
 mod database;
 mod indexer;
+mod oci;
+#[path = "../protocol.rs"]
+mod protocol;
 mod server;
-use args::Args;
-use commands::{index_image, list_images};
+mod verify;
+use args::{Args, Commands};
+use commands::{
+    collect_garbage, find_hash, index_archive, index_image, list_directory, list_images,
+    remove_image, verify_images,
+};
 use database::AppState;
 use server::run_tcp_server;
 
@@ -28,13 +38,38 @@ async fn main() -> Result<()> {
     }
 
     // Initialize application state
-    let app_state = AppState::new(db_path, output_folder).await?;
+    let app_state = AppState::new(db_path, output_folder, args.chunking, args.compression).await?;
 
+    // One-shot commands return their error so the process exits non-zero
+    match args.command {
+        Commands::Index { image_name } => index_image(&image_name, &app_state).await,
+        Commands::IndexArchive { path, image_name } => {
+            index_archive(&path, &image_name, &app_state).await
+        }
+        Commands::List => list_images(&app_state).await,
+        Commands::ListDir { image_name, path } => {
+            list_directory(&image_name, &path, &app_state).await
+        }
+        Commands::FindHash { hash } => find_hash(&hash, &app_state).await,
+        Commands::Remove { image_name } => remove_image(&image_name, &app_state).await,
+        Commands::Gc => collect_garbage(&app_state).await,
+        Commands::Verify {
+            image_name,
+            repair,
+            source,
+        } => verify_images(image_name.as_deref(), repair, source.as_deref(), &app_state).await,
+        Commands::Serve => run_tcp_server(app_state, &args.host, args.port).await,
+        Commands::Shell => run_shell(app_state, args.host, args.port).await,
+    }
+}
+
+/// Serve images while reading indexing commands from stdin
+async fn run_shell(app_state: AppState, host: String, port: u16) -> Result<()> {
     // Start the TCP server in the background
     let tcp_server = tokio::spawn({
         let app_state = app_state.clone();
         async move {
-            if let Err(e) = run_tcp_server(app_state, &args.host, args.port).await {
+            if let Err(e) = run_tcp_server(app_state, &host, port).await {
                 error!("TCP server error: {:?}", e);
             }
         }
@@ -76,6 +111,42 @@ async fn handle_commands(app_state: AppState) {
                     error!("Error indexing image: {:?}", e);
                 }
             }
+            Command::IndexArchive(path, image_name) => {
+                info!("Indexing image: {} from {}", image_name, path);
+                println!("Indexing image: {} from {}", image_name, path);
+                if let Err(e) = index_archive(Path::new(&path), &image_name, &app_state).await {
+                    error!("Error indexing image: {:?}", e);
+                }
+            }
+            Command::ListDir(image_name, path) => {
+                if let Err(e) = list_directory(&image_name, &path, &app_state).await {
+                    error!("Error listing directory: {:?}", e);
+                }
+            }
+            Command::FindHash(hash) => {
+                if let Err(e) = find_hash(&hash, &app_state).await {
+                    error!("Error finding images: {:?}", e);
+                }
+            }
+            Command::Remove(image_name) => {
+                info!("Removing image: {}", image_name);
+                if let Err(e) = remove_image(&image_name, &app_state).await {
+                    error!("Error removing image: {:?}", e);
+                }
+            }
+            Command::Gc => {
+                info!("Collecting unreferenced content");
+                if let Err(e) = collect_garbage(&app_state).await {
+                    error!("Error collecting garbage: {:?}", e);
+                }
+            }
+            Command::Verify(image_name, repair) => {
+                info!("Verifying content");
+                if let Err(e) = verify_images(image_name.as_deref(), repair, None, &app_state).await
+                {
+                    error!("Error verifying images: {:?}", e);
+                }
+            }
             Command::Help => {
                 print_help();
             }
@@ -94,6 +165,12 @@ async fn handle_commands(app_state: AppState) {
 enum Command {
     List,
     Index(String),
+    IndexArchive(String, String),
+    ListDir(String, String),
+    FindHash(String),
+    Remove(String),
+    Gc,
+    Verify(Option<String>, bool),
     Help,
     Unknown(String),
 }
@@ -111,6 +188,56 @@ fn parse_command(input: &str) -> Command {
                 Command::Index(image_name)
             }
         }
+        Some("index-archive") => match (parts.next(), parts.next(), parts.next()) {
+            (Some(path), Some(image_name), None) => {
+                Command::IndexArchive(path.to_string(), image_name.to_string())
+            }
+            _ => {
+                println!("Usage: index-archive <path> <image_name>");
+                Command::Unknown("index-archive".to_string())
+            }
+        },
+        Some("list-dir") => match (parts.next(), parts.next(), parts.next()) {
+            (Some(image_name), path, None) => {
+                Command::ListDir(image_name.to_string(), path.unwrap_or("/").to_string())
+            }
+            _ => {
+                println!("Usage: list-dir <image_name> [path]");
+                Command::Unknown("list-dir".to_string())
+            }
+        },
+        Some("find-hash") => match (parts.next(), parts.next()) {
+            (Some(hash), None) => Command::FindHash(hash.to_string()),
+            _ => {
+                println!("Usage: find-hash <hash>");
+                Command::Unknown("find-hash".to_string())
+            }
+        },
+        Some("remove") => {
+            let image_name = parts.collect::<Vec<&str>>().join(" ");
+            if image_name.is_empty() {
+                println!("Usage: remove <image_name>");
+                Command::Unknown("remove".to_string())
+            } else {
+                Command::Remove(image_name)
+            }
+        }
+        Some("gc") => Command::Gc,
+        Some("verify") => {
+            let mut repair = false;
+            let mut image_name = None;
+            for part in parts {
+                match part {
+                    "--repair" => repair = true,
+                    name if image_name.is_none() => image_name = Some(name.to_string()),
+                    _ => {
+                        println!("Usage: verify [--repair] [image_name]");
+                        return Command::Unknown("verify".to_string());
+                    }
+                }
+            }
+            Command::Verify(image_name, repair)
+        }
         Some("help") => Command::Help,
         Some(cmd) => Command::Unknown(cmd.to_string()),
         None => Command::Unknown(String::new()),
@@ -120,8 +247,14 @@ fn parse_command(input: &str) -> Command {
 fn print_help() {
     println!(
         "\nAvailable commands:
-    ls              - List indexed images
-    index <name>    - Index an image by name
-    help            - Show this help message\n"
+    ls                          - List indexed images
+    index <name>                - Index an image by name
+    index-archive <path> <name> - Index an OCI layout or saved image tarball
+    list-dir <name> [path]      - List a directory of an indexed image
+    find-hash <hash>            - List the images containing a blob
+    remove <name>               - Remove an indexed image
+    gc                          - Delete content no indexed image references
+    verify [--repair] [name]    - Check stored content against indexed images
+    help                        - Show this help message\n"
     );
 }
@@ -0,0 +1,908 @@
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use fuser::FileType;
+use log::{debug, info, warn};
+use nix::errno::Errno;
+use nix::sys::stat::{makedev, mknod, Mode, SFlag};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use tar::{Archive, Entry, EntryType, Header};
+
+use crate::indexer::RecordedMetadata;
+
+/// Prefix of the files marking a path from a lower layer as deleted
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+
+/// Marker hiding every lower layer entry of the directory it is in
+const OPAQUE_WHITEOUT: &[u8] = b".wh..wh..opq";
+
+/// Prefix of the whiteouts AUFS reserves for its own bookkeeping, of which
+/// only `OPAQUE_WHITEOUT` means anything to an image
+const RESERVED_WHITEOUT_PREFIX: &[u8] = b".wh..wh.";
+
+/// Upper bound on the symlinks followed while resolving a path, as in Linux
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// Prefix of the PAX records carrying extended attributes
+const PAX_XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+#[derive(Deserialize)]
+struct OciIndex {
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Deserialize)]
+struct OciManifest {
+    layers: Vec<Descriptor>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    media_type: Option<String>,
+    digest: String,
+    platform: Option<Platform>,
+}
+
+#[derive(Deserialize, Clone)]
+struct Platform {
+    architecture: String,
+    os: String,
+}
+
+/// Entry of the `manifest.json` written by `docker save`
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerManifest {
+    layers: Vec<String>,
+}
+
+/// Root filesystem of an unpacked image
+pub struct UnpackedImage {
+    pub rootfs: PathBuf,
+    /// Ownership and extended attributes of every entry and the device
+    /// nodes that could not be created, by path relative to `rootfs`, since
+    /// unpacking without root cannot reproduce them on disk
+    pub recorded: HashMap<PathBuf, RecordedMetadata>,
+}
+
+/// Unpack an OCI image layout directory, or a `docker save`/`podman save`
+/// tarball, into `work_dir`. Layers are applied in order along with their
+/// whiteouts, without running a container.
+pub fn unpack_image(source: &Path, work_dir: &Path) -> Result<UnpackedImage> {
+    let layout = if source.is_dir() {
+        source.to_path_buf()
+    } else {
+        let layout = work_dir.join("archive");
+        fs::create_dir_all(&layout)?;
+        Archive::new(open_maybe_compressed(source)?)
+            .unpack(&layout)
+            .with_context(|| format!("Failed to unpack {}", source.display()))?;
+        layout
+    };
+
+    let layers = layer_paths(&layout)?;
+    info!("Applying {} layers", layers.len());
+
+    let rootfs = work_dir.join("rootfs");
+    fs::create_dir_all(&rootfs)?;
+
+    let mut dir_modes = HashMap::new();
+    let mut recorded = HashMap::new();
+    for (i, layer) in layers.iter().enumerate() {
+        debug!(
+            "Applying layer {}/{}: {}",
+            i + 1,
+            layers.len(),
+            layer.display()
+        );
+        apply_layer(layer, &rootfs, &mut dir_modes, &mut recorded)
+            .with_context(|| format!("Failed to apply layer {}", layer.display()))?;
+    }
+
+    restore_dir_modes(&rootfs, dir_modes)?;
+
+    Ok(UnpackedImage { rootfs, recorded })
+}
+
+/// Remove a work directory created by `unpack_image`, including the
+/// read-only directories it contains
+pub fn remove_work_dir(work_dir: &Path) -> Result<()> {
+    if !work_dir.exists() {
+        return Ok(());
+    }
+
+    for entry in walkdir::WalkDir::new(work_dir) {
+        let entry = entry?;
+        if entry.file_type().is_dir() {
+            let mode = entry.metadata()?.permissions().mode();
+            fs::set_permissions(entry.path(), fs::Permissions::from_mode(mode | 0o700))?;
+        }
+    }
+
+    fs::remove_dir_all(work_dir)?;
+    Ok(())
+}
+
+/// Paths of the layer tarballs of an image, bottom layer first
+fn layer_paths(layout: &Path) -> Result<Vec<PathBuf>> {
+    if layout.join("index.json").exists() {
+        return oci_layer_paths(layout);
+    }
+
+    if layout.join("manifest.json").exists() {
+        let manifests: Vec<DockerManifest> = read_json(&layout.join("manifest.json"))?;
+        let manifest = match manifests.into_iter().next() {
+            Some(manifest) => manifest,
+            None => bail!("manifest.json does not list any image"),
+        };
+
+        return manifest
+            .layers
+            .iter()
+            .map(|layer| match normalize(Path::new(layer)) {
+                Some(path) => Ok(layout.join(path)),
+                None => bail!("Invalid layer path {}", layer),
+            })
+            .collect();
+    }
+
+    bail!(
+        "{} is neither an OCI image layout nor a docker save archive",
+        layout.display()
+    )
+}
+
+/// Resolve `index.json` down to an image manifest and return its layers
+fn oci_layer_paths(layout: &Path) -> Result<Vec<PathBuf>> {
+    let index: OciIndex = read_json(&layout.join("index.json"))?;
+    let mut descriptor = select_manifest(&index.manifests)?;
+
+    loop {
+        let blob = blob_path(layout, &descriptor.digest)?;
+        let is_index = descriptor.media_type.as_deref().is_some_and(|media_type| {
+            media_type.ends_with("image.index.v1+json")
+                || media_type.ends_with("manifest.list.v2+json")
+        });
+
+        if is_index {
+            let index: OciIndex = read_json(&blob)?;
+            descriptor = select_manifest(&index.manifests)?;
+            continue;
+        }
+
+        let manifest: OciManifest = read_json(&blob)?;
+        return manifest
+            .layers
+            .iter()
+            .map(|layer| blob_path(layout, &layer.digest))
+            .collect();
+    }
+}
+
+/// Pick the manifest matching the host platform, or the first one if none
+/// of them declares a platform
+fn select_manifest(manifests: &[Descriptor]) -> Result<Descriptor> {
+    let architecture = match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        other => other,
+    };
+
+    manifests
+        .iter()
+        .find(|descriptor| {
+            descriptor.platform.as_ref().is_some_and(|platform| {
+                platform.os == "linux" && platform.architecture == architecture
+            })
+        })
+        .or_else(|| manifests.first())
+        .cloned()
+        .context("Image index does not list any manifest")
+}
+
+fn blob_path(layout: &Path, digest: &str) -> Result<PathBuf> {
+    match digest.split_once(':') {
+        Some((algorithm, hex))
+            if !algorithm.is_empty()
+                && algorithm.chars().all(|c| c.is_ascii_alphanumeric())
+                && !hex.is_empty()
+                && hex.chars().all(|c| c.is_ascii_hexdigit()) =>
+        {
+            Ok(layout.join("blobs").join(algorithm).join(hex))
+        }
+        _ => bail!("Invalid digest {}", digest),
+    }
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    serde_json::from_reader(BufReader::new(file))
+        .with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+/// Open a tarball, transparently decompressing it if it is gzipped
+fn open_maybe_compressed(path: &Path) -> Result<Box<dyn Read>> {
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+    );
+    let magic = reader.fill_buf()?;
+
+    if magic.starts_with(&[0x1f, 0x8b]) {
+        Ok(Box::new(GzDecoder::new(reader)))
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        bail!(
+            "{} is zstd compressed, which is not supported",
+            path.display()
+        )
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Apply one layer on top of `rootfs`. Directories are kept writable while
+/// layers are applied; their modes are collected in `dir_modes` and restored
+/// once every layer is in. The metadata of the entries is kept in `recorded`.
+///
+/// Lower layers may have left symlinks pointing anywhere, so every path is
+/// resolved with `resolve_in_root` before anything is created or removed.
+/// Paths in `dir_modes`, `recorded` and `written` are the resolved ones,
+/// relative to `rootfs`.
+fn apply_layer(
+    layer: &Path,
+    rootfs: &Path,
+    dir_modes: &mut HashMap<PathBuf, u32>,
+    recorded: &mut HashMap<PathBuf, RecordedMetadata>,
+) -> Result<()> {
+    let mut archive = Archive::new(open_maybe_compressed(layer)?);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+
+    // Paths written by this layer, which its own opaque whiteouts must keep
+    let mut written = HashSet::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = match normalize(&entry.path()?) {
+            Some(path) => path,
+            None => {
+                warn!("Skipping entry outside of the root: {:?}", entry.path()?);
+                continue;
+            }
+        };
+
+        let file_name = match path.file_name() {
+            Some(name) => name.as_bytes(),
+            None => continue,
+        };
+
+        if file_name == OPAQUE_WHITEOUT {
+            let marker = resolve_in_root(rootfs, &path)?;
+            let dir = marker.parent().unwrap_or(rootfs);
+            clear_dir(dir, rootfs, &written)?;
+
+            let dir = dir.strip_prefix(rootfs)?;
+            recorded.retain(|recorded_path, _| {
+                match recorded_path
+                    .strip_prefix(dir)
+                    .map(|rest| rest.components().next())
+                {
+                    Ok(Some(child)) => written.contains(&dir.join(child)),
+                    _ => true,
+                }
+            });
+            continue;
+        }
+
+        if file_name.starts_with(RESERVED_WHITEOUT_PREFIX) {
+            warn!("Skipping reserved whiteout {}", path.display());
+            continue;
+        }
+
+        if let Some(hidden) = file_name.strip_prefix(WHITEOUT_PREFIX) {
+            // A whiteout hides a single entry of its own directory
+            if matches!(hidden, b"" | b"." | b"..") {
+                bail!("Invalid whiteout {}", path.display());
+            }
+
+            let hidden = resolve_in_root(rootfs, &path.with_file_name(OsStr::from_bytes(hidden)))?;
+            remove_path(&hidden)?;
+            let hidden = hidden.strip_prefix(rootfs)?;
+            recorded.retain(|recorded_path, _| !recorded_path.starts_with(hidden));
+            continue;
+        }
+
+        let target = resolve_in_root(rootfs, &path)?;
+        let path = target.strip_prefix(rootfs)?.to_path_buf();
+        let entry_type = entry.header().entry_type();
+
+        // An entry replaces whatever a lower layer had at the same path,
+        // except that directories are merged
+        if let Ok(existing) = fs::symlink_metadata(&target) {
+            if !(existing.is_dir() && entry_type.is_dir()) {
+                remove_path(&target)?;
+            }
+        }
+
+        let xattrs = read_pax_xattrs(&mut entry)?;
+        let header = entry.header();
+        let mut metadata = RecordedMetadata {
+            uid: header.uid()? as u32,
+            gid: header.gid()? as u32,
+            device: None,
+            xattrs,
+        };
+
+        match entry_type {
+            EntryType::Char | EntryType::Block | EntryType::Fifo => {
+                metadata.device = create_special_file(&entry, &target)?;
+            }
+            EntryType::Directory => {
+                unpack_at(&mut entry, rootfs, &target)?;
+                let mode = entry.header().mode()?;
+                dir_modes.insert(path.clone(), mode);
+                fs::set_permissions(&target, fs::Permissions::from_mode(mode | 0o700))?;
+            }
+            _ => {
+                unpack_at(&mut entry, rootfs, &target)?;
+            }
+        }
+
+        recorded.insert(path.clone(), metadata);
+        written.insert(path);
+    }
+
+    Ok(())
+}
+
+/// Unpack an entry at `target`, a path resolved by `resolve_in_root`. The
+/// targets of hard links are resolved the same way.
+fn unpack_at<R: Read>(entry: &mut Entry<R>, rootfs: &Path, target: &Path) -> Result<()> {
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if entry.header().entry_type().is_hard_link() {
+        let link_name = entry
+            .link_name()?
+            .context("Hard link without a target")?
+            .into_owned();
+        let source = match normalize(&link_name) {
+            Some(source) => resolve_in_root(rootfs, &source)?,
+            None => bail!("Hard link to {} outside of the root", link_name.display()),
+        };
+        return fs::hard_link(&source, target).with_context(|| {
+            format!(
+                "Failed to link {} to {}",
+                target.display(),
+                source.display()
+            )
+        });
+    }
+
+    entry
+        .unpack(target)
+        .with_context(|| format!("Failed to unpack {}", target.display()))?;
+    Ok(())
+}
+
+/// Create a FIFO or device node. Device nodes need root, so when the indexer
+/// runs unprivileged an empty file stands in for them, and their kind and
+/// device number are returned to be recorded.
+fn create_special_file<R: Read>(
+    entry: &Entry<R>,
+    target: &Path,
+) -> Result<Option<(FileType, u32)>> {
+    let header = entry.header();
+    let mode = Mode::from_bits_truncate(header.mode()? & 0o7777);
+
+    let (kind, file_type) = match header.entry_type() {
+        EntryType::Char => (SFlag::S_IFCHR, FileType::CharDevice),
+        EntryType::Block => (SFlag::S_IFBLK, FileType::BlockDevice),
+        _ => (SFlag::S_IFIFO, FileType::NamedPipe),
+    };
+
+    // The device fields of FIFOs are meaningless, and often left blank
+    let dev = if kind == SFlag::S_IFIFO {
+        0
+    } else {
+        device_number(header)?
+    };
+
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    match mknod(target, kind, mode, dev) {
+        Ok(()) => Ok(None),
+        Err(Errno::EPERM) => {
+            debug!(
+                "Recording device node {} as an empty file, creating it requires root",
+                target.display()
+            );
+            File::create(target)?;
+            fs::set_permissions(target, fs::Permissions::from_mode(mode.bits()))?;
+            Ok(Some((file_type, dev as u32)))
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to create {}", target.display())),
+    }
+}
+
+/// Device number of a character or block device entry
+fn device_number(header: &Header) -> Result<u64> {
+    let (major, minor) = match (header.as_ustar(), header.as_gnu()) {
+        (Some(ustar), _) => (Some(&ustar.dev_major), Some(&ustar.dev_minor)),
+        (_, Some(gnu)) => (Some(&gnu.dev_major), Some(&gnu.dev_minor)),
+        _ => (None, None),
+    };
+
+    Ok(makedev(
+        device_field(header.device_major(), major)?,
+        device_field(header.device_minor(), minor)?,
+    ))
+}
+
+/// Value of a device field, reading a blank field, which some archivers
+/// write, as 0
+fn device_field(parsed: std::io::Result<Option<u32>>, raw: Option<&[u8; 8]>) -> Result<u64> {
+    match raw {
+        Some(raw) if raw.iter().all(|&byte| byte == 0 || byte == b' ') => Ok(0),
+        _ => Ok(parsed?.unwrap_or(0) as u64),
+    }
+}
+
+/// Extended attributes of an entry, carried in its PAX records
+fn read_pax_xattrs<R: Read>(entry: &mut Entry<R>) -> Result<BTreeMap<String, Vec<u8>>> {
+    let mut xattrs = BTreeMap::new();
+
+    if let Some(extensions) = entry.pax_extensions()? {
+        for extension in extensions {
+            let extension = extension?;
+            let name = match extension.key() {
+                Ok(key) => key.strip_prefix(PAX_XATTR_PREFIX),
+                Err(_) => None,
+            };
+            if let Some(name) = name {
+                xattrs.insert(name.to_string(), extension.value_bytes().to_vec());
+            }
+        }
+    }
+
+    Ok(xattrs)
+}
+
+/// Remove the entries lower layers left in `dir`
+fn clear_dir(dir: &Path, rootfs: &Path, written: &HashSet<PathBuf>) -> Result<()> {
+    let children = match fs::read_dir(dir) {
+        Ok(children) => children,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    for child in children {
+        let child = child?.path();
+        let relative = child.strip_prefix(rootfs)?;
+        if !written.contains(relative) {
+            remove_path(&child)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn remove_path(path: &Path) -> Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    if metadata.is_dir() {
+        fs::remove_dir_all(path)?;
+    } else {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Put back the modes of the directories unpacked from the layers, deepest
+/// first so parents stay traversable until their children are done
+fn restore_dir_modes(rootfs: &Path, dir_modes: HashMap<PathBuf, u32>) -> Result<()> {
+    let mut dirs: Vec<_> = dir_modes.into_iter().collect();
+    dirs.sort_by_key(|(path, _)| std::cmp::Reverse(path.components().count()));
+
+    for (path, mode) in dirs {
+        // Skip directories a later layer removed or replaced, along with
+        // those under a parent it replaced with a symlink
+        let dir = rootfs.join(&path);
+        let is_dir = fs::symlink_metadata(&dir).is_ok_and(|metadata| metadata.is_dir());
+        if !is_dir || resolve_in_root(rootfs, &path)? != dir {
+            continue;
+        }
+
+        fs::set_permissions(&dir, fs::Permissions::from_mode(mode))?;
+    }
+
+    Ok(())
+}
+
+/// Resolve `path`, relative to `rootfs`, the way it would be resolved with
+/// `rootfs` as the root directory: symlinks in its parent directories are
+/// followed, with absolute targets and `..` kept inside `rootfs`. The last
+/// component is not followed, so the entry itself can be replaced or
+/// removed.
+fn resolve_in_root(rootfs: &Path, path: &Path) -> Result<PathBuf> {
+    let file_name = match path.file_name() {
+        Some(file_name) => file_name,
+        None => return Ok(rootfs.to_path_buf()),
+    };
+
+    // Components left to resolve, the next one last
+    let mut pending = Vec::new();
+    push_components(&mut pending, path.parent().unwrap_or(Path::new("")));
+
+    let mut resolved = PathBuf::new();
+    let mut hops = 0;
+    while let Some(part) = pending.pop() {
+        if part == ".." {
+            resolved.pop();
+            continue;
+        }
+
+        let candidate = resolved.join(&part);
+        match fs::symlink_metadata(rootfs.join(&candidate)) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                hops += 1;
+                if hops > MAX_SYMLINK_HOPS {
+                    bail!("Too many levels of symlinks in {}", path.display());
+                }
+
+                let link = fs::read_link(rootfs.join(&candidate))?;
+                if link.is_absolute() {
+                    resolved = PathBuf::new();
+                }
+                push_components(&mut pending, &link);
+            }
+            // Whatever does not exist yet gets created as a directory
+            _ => resolved = candidate,
+        }
+    }
+
+    Ok(rootfs.join(resolved).join(file_name))
+}
+
+/// Push the components of `path` to be resolved by `resolve_in_root`
+fn push_components(pending: &mut Vec<OsString>, path: &Path) {
+    for component in path.components().rev() {
+        match component {
+            Component::Normal(part) => pending.push(part.to_os_string()),
+            Component::ParentDir => pending.push(OsString::from("..")),
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+        }
+    }
+}
+
+/// Turn an archive path into a relative path with no `.` or `..` components,
+/// or `None` if it would escape the root
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::CurDir | Component::RootDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tar::{Builder, Header};
+
+    enum Item<'a> {
+        Dir(&'a str),
+        File(&'a str),
+        Symlink(&'a str, &'a Path),
+        Fifo(&'a str),
+    }
+
+    /// Empty directory unique to a test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("silo-oci-{}-{}", std::process::id(), name));
+        let _ = remove_work_dir(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_layer(path: &Path, items: &[Item]) {
+        let mut builder = Builder::new(File::create(path).unwrap());
+        for item in items {
+            let mut header = Header::new_ustar();
+            header.set_size(0);
+            header.set_mtime(1);
+            header.set_uid(0);
+            header.set_gid(0);
+            match item {
+                Item::Dir(name) => {
+                    header.set_entry_type(EntryType::Directory);
+                    header.set_mode(0o755);
+                    builder.append_data(&mut header, name, &[][..]).unwrap();
+                }
+                Item::File(name) => {
+                    header.set_entry_type(EntryType::Regular);
+                    header.set_mode(0o644);
+                    header.set_size(name.len() as u64);
+                    builder
+                        .append_data(&mut header, name, name.as_bytes())
+                        .unwrap();
+                }
+                Item::Symlink(name, target) => {
+                    header.set_entry_type(EntryType::Symlink);
+                    header.set_mode(0o777);
+                    builder.append_link(&mut header, name, target).unwrap();
+                }
+                Item::Fifo(name) => {
+                    header.set_entry_type(EntryType::Fifo);
+                    header.set_mode(0o644);
+                    builder.append_data(&mut header, name, &[][..]).unwrap();
+                }
+            }
+        }
+        builder.finish().unwrap();
+    }
+
+    struct Applied {
+        rootfs: PathBuf,
+        recorded: HashMap<PathBuf, RecordedMetadata>,
+    }
+
+    /// Apply layers in order on an empty root filesystem in `dir`
+    fn apply_layers(dir: &Path, layers: &[&[Item]]) -> Result<Applied> {
+        let rootfs = dir.join("rootfs");
+        fs::create_dir_all(&rootfs)?;
+
+        let mut dir_modes = HashMap::new();
+        let mut recorded = HashMap::new();
+        for (i, items) in layers.iter().enumerate() {
+            let layer = dir.join(format!("layer{}.tar", i));
+            write_layer(&layer, items);
+            apply_layer(&layer, &rootfs, &mut dir_modes, &mut recorded)?;
+        }
+        restore_dir_modes(&rootfs, dir_modes)?;
+
+        Ok(Applied { rootfs, recorded })
+    }
+
+    #[test]
+    fn whiteout_removes_lower_entry() {
+        let dir = test_dir("whiteout");
+        let applied = apply_layers(
+            &dir,
+            &[
+                &[Item::Dir("etc"), Item::File("etc/a"), Item::File("etc/b")],
+                &[Item::File("etc/.wh.a")],
+            ],
+        )
+        .unwrap();
+
+        assert!(!applied.rootfs.join("etc/a").exists());
+        assert!(!applied.rootfs.join("etc/.wh.a").exists());
+        assert!(applied.rootfs.join("etc/b").exists());
+        assert!(!applied.recorded.contains_key(Path::new("etc/a")));
+        assert!(applied.recorded.contains_key(Path::new("etc/b")));
+        remove_work_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn whiteout_removes_lower_directory_tree() {
+        let dir = test_dir("whiteout-dir");
+        let applied = apply_layers(
+            &dir,
+            &[
+                &[
+                    Item::Dir("opt"),
+                    Item::Dir("opt/app"),
+                    Item::File("opt/app/bin"),
+                ],
+                &[Item::File("opt/.wh.app")],
+            ],
+        )
+        .unwrap();
+
+        assert!(!applied.rootfs.join("opt/app").exists());
+        assert!(applied.rootfs.join("opt").is_dir());
+        assert!(!applied.recorded.contains_key(Path::new("opt/app/bin")));
+        remove_work_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn opaque_whiteout_keeps_only_entries_of_its_own_layer() {
+        let dir = test_dir("opaque");
+        let applied = apply_layers(
+            &dir,
+            &[
+                &[Item::Dir("d"), Item::File("d/old"), Item::File("kept")],
+                &[Item::File("d/new"), Item::File("d/.wh..wh..opq")],
+            ],
+        )
+        .unwrap();
+
+        assert!(!applied.rootfs.join("d/old").exists());
+        assert!(applied.rootfs.join("d/new").exists());
+        assert!(applied.rootfs.join("kept").exists());
+        assert!(!applied.recorded.contains_key(Path::new("d/old")));
+        assert!(applied.recorded.contains_key(Path::new("d/new")));
+        remove_work_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn whiteout_through_symlink_stays_inside_root() {
+        let dir = test_dir("escape-whiteout");
+        let victim = dir.join("victim");
+        fs::create_dir_all(&victim).unwrap();
+        fs::write(victim.join("secret"), "secret").unwrap();
+
+        apply_layers(
+            &dir,
+            &[
+                &[Item::Symlink("evil", &victim)],
+                &[Item::File("evil/.wh.secret")],
+            ],
+        )
+        .unwrap();
+
+        assert!(victim.join("secret").exists());
+        remove_work_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn opaque_whiteout_through_symlink_stays_inside_root() {
+        let dir = test_dir("escape-opaque");
+        let victim = dir.join("victim");
+        fs::create_dir_all(&victim).unwrap();
+        fs::write(victim.join("secret"), "secret").unwrap();
+
+        apply_layers(
+            &dir,
+            &[
+                &[Item::Symlink("evil", Path::new("../../victim"))],
+                &[Item::File("evil/.wh..wh..opq")],
+            ],
+        )
+        .unwrap();
+
+        assert!(victim.join("secret").exists());
+        remove_work_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn entries_through_symlink_stay_inside_root() {
+        let dir = test_dir("escape-entries");
+        let victim = dir.join("victim");
+        fs::create_dir_all(&victim).unwrap();
+        fs::write(victim.join("secret"), "secret").unwrap();
+
+        let applied = apply_layers(
+            &dir,
+            &[
+                &[Item::Symlink("evil", &victim)],
+                &[
+                    Item::File("evil/secret"),
+                    Item::File("evil/planted"),
+                    Item::Fifo("evil/pipe"),
+                ],
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(fs::read_to_string(victim.join("secret")).unwrap(), "secret");
+        assert!(!victim.join("planted").exists());
+        assert!(!victim.join("pipe").exists());
+
+        // The absolute target is taken relative to the root
+        let inside = applied
+            .rootfs
+            .join(victim.strip_prefix("/").unwrap())
+            .join("planted");
+        assert!(inside.exists());
+        remove_work_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn replaced_directory_mode_is_not_restored_through_symlink() {
+        let dir = test_dir("escape-mode");
+        let victim = dir.join("victim");
+        fs::create_dir_all(victim.join("sub")).unwrap();
+        for dir in [&victim, &victim.join("sub")] {
+            fs::set_permissions(dir, fs::Permissions::from_mode(0o700)).unwrap();
+        }
+
+        apply_layers(
+            &dir,
+            &[
+                &[Item::Dir("d"), Item::Dir("d/sub")],
+                &[Item::Symlink("d", &victim)],
+                &[Item::Dir("d/sub")],
+            ],
+        )
+        .unwrap();
+
+        for dir in [&victim, &victim.join("sub")] {
+            let mode = fs::metadata(dir).unwrap().permissions().mode() & 0o7777;
+            assert_eq!(mode, 0o700, "{} was changed", dir.display());
+        }
+        remove_work_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn whiteouts_of_dot_entries_are_rejected() {
+        for name in ["d/.wh..", "d/.wh...", "d/.wh."] {
+            let dir = test_dir("dot-whiteout");
+            let result = apply_layers(
+                &dir,
+                &[&[Item::Dir("d"), Item::File("d/file")], &[Item::File(name)]],
+            );
+
+            assert!(result.is_err(), "{} was accepted", name);
+            assert!(dir.join("rootfs/d/file").exists());
+            remove_work_dir(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn reserved_whiteouts_are_skipped() {
+        let dir = test_dir("reserved-whiteout");
+        let applied = apply_layers(
+            &dir,
+            &[
+                &[Item::Dir("d"), Item::File("d/file")],
+                &[Item::File("d/.wh..wh.plnk")],
+            ],
+        )
+        .unwrap();
+
+        assert!(applied.rootfs.join("d/file").exists());
+        assert!(!applied.rootfs.join("d/.wh..wh.plnk").exists());
+        remove_work_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn fifo_with_blank_device_fields_is_created() {
+        // `write_layer` leaves the device fields of its headers blank
+        let dir = test_dir("fifo");
+        let applied = apply_layers(&dir, &[&[Item::Fifo("pipe")]]).unwrap();
+
+        assert!(fs::symlink_metadata(applied.rootfs.join("pipe")).is_ok());
+        remove_work_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn resolve_in_root_keeps_paths_inside_root() {
+        let dir = test_dir("resolve");
+        let rootfs = dir.join("rootfs");
+        fs::create_dir_all(rootfs.join("usr/lib")).unwrap();
+        symlink("usr/lib", rootfs.join("lib")).unwrap();
+        symlink("/usr", rootfs.join("abs")).unwrap();
+        symlink("../../../..", rootfs.join("up")).unwrap();
+        symlink("loop", rootfs.join("loop")).unwrap();
+
+        let resolve = |path: &str| resolve_in_root(&rootfs, Path::new(path)).unwrap();
+        assert_eq!(resolve("lib/libc.so"), rootfs.join("usr/lib/libc.so"));
+        assert_eq!(resolve("abs/lib/libc.so"), rootfs.join("usr/lib/libc.so"));
+        assert_eq!(resolve("up/etc/passwd"), rootfs.join("etc/passwd"));
+        // The last component is never followed
+        assert_eq!(resolve("lib"), rootfs.join("lib"));
+        assert!(resolve_in_root(&rootfs, Path::new("loop/file")).is_err());
+        remove_work_dir(&dir).unwrap();
+    }
+}
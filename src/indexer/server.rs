@@ -2,35 +2,54 @@ use anyhow::Result;
 use log::{debug, error, info};
 use moka::future::Cache;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::io::SeekFrom;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Instant;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tokio::net::TcpListener;
 
+use crate::blobs;
 use crate::database::AppState;
+use crate::protocol::{
+    encode_compressed, encode_inodes, encode_payload, is_supported_version, parse_hello,
+    parse_range_request, parse_trace, EntryName, ErrorCode, Frame, Opcode, FEATURE_ZSTD,
+    MAX_RANGE_LEN, MAX_TRACE_LEN, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
 use fuser::FileAttr;
 
+/// Bytes of decompressed blobs kept in memory
+const CACHE_CAPACITY: u64 = 512 * 1024 * 1024;
+
+/// Optional features this server can enable for a connection
+const SUPPORTED_FEATURES: u32 = FEATURE_ZSTD;
+
 #[derive(Serialize, Deserialize)]
 struct DataToSend {
-    directory_cache: HashMap<u64, HashMap<String, u64>>,
+    /// Entries of every directory as (name, inode)
+    directory_cache: HashMap<u64, Vec<(EntryName, u64)>>,
     file_attr_cache: HashMap<u64, FileAttr>,
     inode_to_hash: HashMap<u64, String>,
+    chunks: HashMap<String, Vec<(String, u64)>>,
+    xattrs: HashMap<u64, BTreeMap<String, Vec<u8>>>,
 }
 
 pub async fn run_tcp_server(state: AppState, host: &str, port: u16) -> Result<()> {
     let addr = format!("{}:{}", host, port);
     let listener = TcpListener::bind(&addr).await?;
 
-
     info!("TCP server listening on {}", addr);
 
-    let cache: Cache<String, Arc<Vec<u8>>> = Cache::new(10_000);
+    let cache: Cache<String, Arc<Vec<u8>>> = Cache::builder()
+        .weigher(|_, blob: &Arc<Vec<u8>>| blob.len().try_into().unwrap_or(u32::MAX))
+        .max_capacity(CACHE_CAPACITY)
+        .build();
 
     loop {
         let (mut socket, addr) = listener.accept().await?;
         socket.set_nodelay(true)?;
-        
+
         let state = state.clone();
         let cache = cache.clone();
 
@@ -49,67 +68,339 @@ async fn handle_client(
     state: &AppState,
     cache: Cache<String, Arc<Vec<u8>>>,
 ) -> Result<()> {
-    let mut buf = [0; 64];
+    let compression = match handshake(socket).await? {
+        Some(compression) => compression,
+        None => return Ok(()),
+    };
 
     loop {
-        match socket.read(&mut buf).await {
-            Ok(0) => {
+        let frame = match Frame::read_from_async(socket).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => {
                 info!("Client disconnected");
                 break;
             }
-            Ok(_) => {
-                let start = Instant::now();
-
-                let request = String::from_utf8_lossy(&buf)
-                    .trim_end_matches('\0')
-                    .to_string();
-
-                if request.starts_with("GET_DATA:") {
-                    let image_name = request.strip_prefix("GET_DATA:").unwrap();
-                    debug!("Received GET_DATA request for image: {}", image_name);
-                    let data = get_data(image_name, state).await?;
-                    let serialized = serde_json::to_vec(&data)?;
-
-                    socket
-                        .write_all(&(serialized.len() as u64).to_be_bytes())
-                        .await?;
-                    socket.write_all(&serialized).await?;
-                } else {
-                    debug!("Received file request: {}", request);
-                    let file_path = state.output_folder.join(&request);
-
-                    if let Some(file) = cache.get(&request).await {
-                        socket.write_all(&(file.len() as u64).to_be_bytes()).await?;
-                        socket.write_all(&file).await?;
-                    } else {
-                        let file = tokio::fs::read(&file_path).await?;
-                        socket.write_all(&(file.len() as u64).to_be_bytes()).await?;
-                        socket.write_all(&file).await?;
-                        cache.insert(request, Arc::new(file)).await;
-                    }
-                }
-
-                // print that takes more than 2ms to read the content
-                if start.elapsed().as_millis() > 2 {
-                    info!("Read content in {}ms", start.elapsed().as_millis());
-                }
-            }
             Err(e) => {
                 error!("Failed to read from socket: {:?}", e);
                 break;
             }
+        };
+
+        let start = Instant::now();
+        let request_id = frame.request_id;
+
+        let response = match handle_request(frame, state, &cache, compression).await {
+            // The client would drop the connection on reading it
+            Ok(response) if response.check_len().is_err() => {
+                let message = format!(
+                    "{:?} response of {} bytes is too large",
+                    response.opcode,
+                    response.payload.len()
+                );
+                error!("Request {} failed: {}", request_id, message);
+                Frame::error(request_id, ErrorCode::Internal, &message)
+            }
+            Ok(response) => response,
+            Err((code, message)) => {
+                debug!("Request {} failed: {}", request_id, message);
+                Frame::error(request_id, code, &message)
+            }
+        };
+        response.write_to_async(socket).await?;
+
+        // print that takes more than 2ms to read the content
+        if start.elapsed().as_millis() > 2 {
+            info!("Read content in {}ms", start.elapsed().as_millis());
         }
     }
 
     Ok(())
 }
 
+/// Exchange `Hello` frames with a new client, returning `None` if the
+/// connection should not be served and otherwise whether payloads are
+/// compressed on it
+async fn handshake(socket: &mut tokio::net::TcpStream) -> Result<Option<bool>> {
+    let frame = match Frame::read_from_async(socket).await? {
+        Some(frame) => frame,
+        None => return Ok(None),
+    };
+
+    if frame.opcode != Opcode::Hello {
+        Frame::error(
+            frame.request_id,
+            ErrorCode::BadRequest,
+            "Expected a hello frame",
+        )
+        .write_to_async(socket)
+        .await?;
+        return Ok(None);
+    }
+
+    let (version, features) = parse_hello(&frame.payload)?;
+    if !is_supported_version(version) {
+        error!("Rejecting client with protocol version {}", version);
+        Frame::error(
+            frame.request_id,
+            ErrorCode::UnsupportedVersion,
+            &format!(
+                "Protocol version {} is not supported, expected {}..={}",
+                version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+        )
+        .write_to_async(socket)
+        .await?;
+        return Ok(None);
+    }
+
+    let enabled = features & SUPPORTED_FEATURES;
+    Frame::hello(frame.request_id, enabled)
+        .write_to_async(socket)
+        .await?;
+    Ok(Some(enabled & FEATURE_ZSTD != 0))
+}
+
+/// Serve a single request frame
+async fn handle_request(
+    frame: Frame,
+    state: &AppState,
+    cache: &Cache<String, Arc<Vec<u8>>>,
+    compression: bool,
+) -> Result<Frame, (ErrorCode, String)> {
+    let request_id = frame.request_id;
+    let encode = |data: Vec<u8>| {
+        if compression {
+            encode_payload(data)
+        } else {
+            data
+        }
+    };
+
+    match frame.opcode {
+        Opcode::GetImageData => {
+            let image_name = String::from_utf8(frame.payload)
+                .map_err(|_| (ErrorCode::BadRequest, "Image name is not UTF-8".to_string()))?;
+            debug!("Received GET_DATA request for image: {}", image_name);
+
+            let data = get_data(&image_name, state)
+                .await
+                .map_err(|e| (ErrorCode::NotFound, format!("{}: {}", image_name, e)))?;
+            let serialized =
+                serde_json::to_vec(&data).map_err(|e| (ErrorCode::Internal, e.to_string()))?;
+
+            Ok(Frame::new(
+                Opcode::ImageData,
+                request_id,
+                encode(serialized),
+            ))
+        }
+        Opcode::GetContent => {
+            let hash = parse_hash(&frame.payload)?;
+            debug!("Received file request: {}", hash);
+
+            if let Some(file) = cache.get(&hash).await {
+                return Ok(Frame::new(
+                    Opcode::Content,
+                    request_id,
+                    encode(file.to_vec()),
+                ));
+            }
+
+            // A blob stored compressed goes out as stored to clients that
+            // can decompress it
+            let file = match read_compressed(&state.output_folder, &hash)
+                .await
+                .map_err(|e| blob_error(&hash, e))?
+            {
+                Some(stored) if compression => {
+                    return Ok(Frame::new(
+                        Opcode::Content,
+                        request_id,
+                        encode_compressed(&stored),
+                    ));
+                }
+                Some(stored) => decompress(stored).await,
+                None => tokio::fs::read(blobs::raw_path(&state.output_folder, &hash)).await,
+            }
+            .map(Arc::new)
+            .map_err(|e| blob_error(&hash, e))?;
+            cache.insert(hash, file.clone()).await;
+
+            Ok(Frame::new(
+                Opcode::Content,
+                request_id,
+                encode(file.to_vec()),
+            ))
+        }
+        Opcode::ReadRange => {
+            let (hash, offset, length) = parse_range_request(&frame.payload)
+                .map_err(|e| (ErrorCode::BadRequest, e.to_string()))?;
+            let hash = parse_hash(hash)?;
+            debug!(
+                "Received range request: {} offset={} length={}",
+                hash, offset, length
+            );
+
+            if length > MAX_RANGE_LEN {
+                return Err((
+                    ErrorCode::BadRequest,
+                    format!("Range of {} bytes exceeds {}", length, MAX_RANGE_LEN),
+                ));
+            }
+
+            let file = match cache.get(&hash).await {
+                Some(file) => file,
+                None => {
+                    let stored = match read_compressed(&state.output_folder, &hash)
+                        .await
+                        .map_err(|e| blob_error(&hash, e))?
+                    {
+                        Some(stored) => stored,
+                        None => {
+                            let path = blobs::raw_path(&state.output_folder, &hash);
+                            let data = read_range(&path, offset, length)
+                                .await
+                                .map_err(|e| blob_error(&hash, e))?;
+                            return Ok(Frame::new(Opcode::Range, request_id, encode(data)));
+                        }
+                    };
+
+                    // A range covering the whole blob goes out as stored to
+                    // clients that can decompress it
+                    let size = zstd::zstd_safe::get_frame_content_size(&stored)
+                        .ok()
+                        .flatten();
+                    if compression && offset == 0 && size.is_some_and(|size| length as u64 >= size)
+                    {
+                        return Ok(Frame::new(
+                            Opcode::Range,
+                            request_id,
+                            encode_compressed(&stored),
+                        ));
+                    }
+
+                    let file = decompress(stored)
+                        .await
+                        .map(Arc::new)
+                        .map_err(|e| blob_error(&hash, e))?;
+                    cache.insert(hash, file.clone()).await;
+                    file
+                }
+            };
+
+            let start = (offset as usize).min(file.len());
+            let end = start.saturating_add(length as usize).min(file.len());
+            Ok(Frame::new(
+                Opcode::Range,
+                request_id,
+                encode(file[start..end].to_vec()),
+            ))
+        }
+        Opcode::PutTrace => {
+            let (image_name, inodes) =
+                parse_trace(&frame.payload).map_err(|e| (ErrorCode::BadRequest, e.to_string()))?;
+            debug!(
+                "Received trace of {} inodes for image: {}",
+                inodes.len(),
+                image_name
+            );
+
+            state
+                .save_trace(&image_name, &inodes)
+                .await
+                .map_err(|e| (ErrorCode::Internal, e.to_string()))?;
+
+            Ok(Frame::new(Opcode::TraceSaved, request_id, Vec::new()))
+        }
+        Opcode::GetTrace => {
+            let image_name = String::from_utf8(frame.payload)
+                .map_err(|_| (ErrorCode::BadRequest, "Image name is not UTF-8".to_string()))?;
+            debug!("Received trace request for image: {}", image_name);
+
+            let inodes = state
+                .get_trace(&image_name)
+                .await
+                .map_err(|e| (ErrorCode::Internal, e.to_string()))?;
+
+            // Traces are recorded with at most this many inodes, but the
+            // table may predate the limit
+            let inodes = &inodes[..inodes.len().min(MAX_TRACE_LEN)];
+            Ok(Frame::new(Opcode::Trace, request_id, encode_inodes(inodes)))
+        }
+        other => Err((
+            ErrorCode::BadRequest,
+            format!("Unexpected {:?} frame", other),
+        )),
+    }
+}
+
+/// Read a blob as stored if it is stored compressed
+async fn read_compressed(folder: &Path, hash: &str) -> std::io::Result<Option<Vec<u8>>> {
+    match tokio::fs::read(blobs::compressed_path(folder, hash)).await {
+        Ok(stored) => Ok(Some(stored)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Decompress a stored blob off the async runtime
+async fn decompress(stored: Vec<u8>) -> std::io::Result<Vec<u8>> {
+    tokio::task::spawn_blocking(move || zstd::stream::decode_all(stored.as_slice()))
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+/// Read up to `length` bytes of a blob from disk without loading all of it
+async fn read_range(path: &Path, offset: u64, length: u32) -> std::io::Result<Vec<u8>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    let mut data = Vec::with_capacity(length as usize);
+    file.take(length as u64).read_to_end(&mut data).await?;
+    Ok(data)
+}
+
+/// Map a failure to load a blob to the error sent back to the client
+fn blob_error(hash: &str, e: std::io::Error) -> (ErrorCode, String) {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => (ErrorCode::NotFound, format!("Blob {} not found", hash)),
+        _ => (ErrorCode::Internal, e.to_string()),
+    }
+}
+
+/// Validate a blob hash so it cannot escape the content folder
+fn parse_hash(payload: &[u8]) -> Result<String, (ErrorCode, String)> {
+    let hash = std::str::from_utf8(payload)
+        .map_err(|_| (ErrorCode::BadRequest, "Hash is not UTF-8".to_string()))?;
+
+    if hash.is_empty() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err((ErrorCode::BadRequest, format!("Invalid hash {:?}", hash)));
+    }
+
+    Ok(hash.to_string())
+}
+
 async fn get_data(image_name: &str, state: &AppState) -> Result<DataToSend> {
     let (directory, file_attr, inode_to_hash) = state.get_image_data(image_name).await?;
+    let chunks = state.get_image_chunks(image_name).await?;
+    let xattrs = state.get_image_xattrs(image_name).await?;
+
+    let directory = directory
+        .into_iter()
+        .map(|(parent, children)| {
+            let children = children
+                .into_iter()
+                .map(|(name, ino)| (EntryName::from(name), ino))
+                .collect();
+            (parent, children)
+        })
+        .collect();
 
     Ok(DataToSend {
         directory_cache: directory,
         file_attr_cache: file_attr,
         inode_to_hash,
+        chunks,
+        xattrs,
     })
 }
@@ -0,0 +1,368 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsString;
+use std::fmt;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use fuser::{FileAttr, FileType, FUSE_ROOT_ID};
+use sha2::{Digest, Sha256};
+
+use crate::blobs;
+
+/// Size of the reads blobs are re-hashed with
+const READ_BUF_SIZE: usize = 256 * 1024;
+
+/// State of the content stored for a file hash
+#[derive(Clone)]
+pub enum ContentState {
+    /// Every blob is stored and hashes to its name, with the file length
+    Intact(u64),
+    /// `blob`, the file itself or one of its chunks, is not stored
+    Missing { blob: String },
+    /// `blob` is stored but its content is not what it is named after
+    Corrupt { blob: String, detail: String },
+}
+
+/// Something wrong with an indexed image or the content it references
+pub enum Issue {
+    MissingContent {
+        ino: u64,
+        hash: String,
+        blob: String,
+    },
+    CorruptContent {
+        ino: u64,
+        hash: String,
+        blob: String,
+        detail: String,
+    },
+    SizeMismatch {
+        ino: u64,
+        expected: u64,
+        actual: u64,
+    },
+    NoContent {
+        ino: u64,
+    },
+    ContentWithoutInode {
+        ino: u64,
+        hash: String,
+    },
+    MissingRoot,
+    DanglingEntry {
+        parent: u64,
+        name: OsString,
+        ino: u64,
+    },
+    EntriesOfNonDirectory {
+        ino: u64,
+    },
+    Unreachable {
+        ino: u64,
+    },
+}
+
+impl Issue {
+    /// Inode, file hash and damaged blob of an issue that re-storing the
+    /// file from its source can repair
+    pub fn damaged_content(&self) -> Option<(u64, &str, &str)> {
+        match self {
+            Issue::MissingContent { ino, hash, blob }
+            | Issue::CorruptContent {
+                ino, hash, blob, ..
+            } => Some((*ino, hash, blob)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::MissingContent { ino, hash, blob } if hash == blob => {
+                write!(f, "inode {}: blob {} is missing", ino, hash)
+            }
+            Issue::MissingContent { ino, hash, blob } => {
+                write!(f, "inode {}: chunk {} of {} is missing", ino, blob, hash)
+            }
+            Issue::CorruptContent {
+                ino, blob, detail, ..
+            } => write!(f, "inode {}: blob {} is corrupt, {}", ino, blob, detail),
+            Issue::SizeMismatch {
+                ino,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "inode {}: size is {} bytes but its content is {} bytes",
+                ino, expected, actual
+            ),
+            Issue::NoContent { ino } => write!(f, "inode {}: no content recorded", ino),
+            Issue::ContentWithoutInode { ino, hash } => {
+                write!(
+                    f,
+                    "inode {}: content {} recorded for a missing inode",
+                    ino, hash
+                )
+            }
+            Issue::MissingRoot => write!(f, "root inode {} is missing", FUSE_ROOT_ID),
+            Issue::DanglingEntry { parent, name, ino } => write!(
+                f,
+                "inode {}: entry {:?} points to missing inode {}",
+                parent, name, ino
+            ),
+            Issue::EntriesOfNonDirectory { ino } => {
+                write!(f, "inode {}: has entries but is not a directory", ino)
+            }
+            Issue::Unreachable { ino } => {
+                write!(f, "inode {}: not reachable from the root", ino)
+            }
+        }
+    }
+}
+
+/// Checks stored content, remembering the state of every file hash so
+/// content shared by several images is only read once
+pub struct ContentChecker<'a> {
+    folder: &'a Path,
+    checked: HashMap<String, ContentState>,
+}
+
+impl<'a> ContentChecker<'a> {
+    pub fn new(folder: &'a Path) -> Self {
+        Self {
+            folder,
+            checked: HashMap::new(),
+        }
+    }
+
+    /// Check the content stored for a file hash, from its chunks if it is
+    /// stored in chunks
+    pub fn check(&mut self, hash: &str, chunks: Option<&[(String, u64)]>) -> ContentState {
+        if let Some(state) = self.checked.get(hash) {
+            return state.clone();
+        }
+
+        let state = match chunks {
+            Some(chunks) => self.check_chunks(hash, chunks),
+            None => self.check_blob(hash),
+        };
+        self.checked.insert(hash.to_string(), state.clone());
+        state
+    }
+
+    /// Drop the remembered state of a file hash, after it was repaired
+    pub fn forget(&mut self, hash: &str) {
+        self.checked.remove(hash);
+    }
+
+    fn check_blob(&self, hash: &str) -> ContentState {
+        match hash_blob(self.folder, hash, None) {
+            Ok(Some((actual, len))) if actual == hash => ContentState::Intact(len),
+            Ok(Some((actual, _))) => ContentState::Corrupt {
+                blob: hash.to_string(),
+                detail: format!("its content hashes to {}", actual),
+            },
+            Ok(None) => ContentState::Missing {
+                blob: hash.to_string(),
+            },
+            Err(e) => ContentState::Corrupt {
+                blob: hash.to_string(),
+                detail: format!("it cannot be read: {}", e),
+            },
+        }
+    }
+
+    fn check_chunks(&self, hash: &str, chunks: &[(String, u64)]) -> ContentState {
+        let mut file = Sha256::new();
+        let mut len = 0;
+
+        for (chunk_hash, chunk_len) in chunks {
+            let detail = match hash_blob(self.folder, chunk_hash, Some(&mut file)) {
+                Ok(Some((actual, n))) if actual == *chunk_hash && n == *chunk_len => {
+                    len += n;
+                    continue;
+                }
+                Ok(Some((actual, _))) if actual != *chunk_hash => {
+                    format!("its content hashes to {}", actual)
+                }
+                Ok(Some((_, n))) => format!("it is {} bytes but {} are listed", n, chunk_len),
+                Ok(None) => {
+                    return ContentState::Missing {
+                        blob: chunk_hash.clone(),
+                    }
+                }
+                Err(e) => format!("it cannot be read: {}", e),
+            };
+
+            return ContentState::Corrupt {
+                blob: chunk_hash.clone(),
+                detail,
+            };
+        }
+
+        let actual = format!("{:x}", file.finalize());
+        if actual != hash {
+            return ContentState::Corrupt {
+                blob: hash.to_string(),
+                detail: format!("its chunks hash to {}", actual),
+            };
+        }
+
+        ContentState::Intact(len)
+    }
+}
+
+/// Check the metadata of an image and the content it references
+pub fn check_image(
+    directory: &HashMap<u64, HashMap<OsString, u64>>,
+    file_attr: &HashMap<u64, FileAttr>,
+    inode_to_hash: &HashMap<u64, String>,
+    chunks: &HashMap<String, Vec<(String, u64)>>,
+    checker: &mut ContentChecker,
+) -> Vec<Issue> {
+    let mut issues = Vec::new();
+
+    let mut inodes: Vec<_> = file_attr.values().collect();
+    inodes.sort_by_key(|attr| attr.ino);
+
+    for attr in inodes {
+        let hash = match inode_to_hash.get(&attr.ino) {
+            Some(hash) => hash,
+            // Special files have no content
+            None if attr.kind == FileType::Symlink
+                || (attr.kind == FileType::RegularFile && attr.size > 0) =>
+            {
+                issues.push(Issue::NoContent { ino: attr.ino });
+                continue;
+            }
+            None => continue,
+        };
+
+        match checker.check(hash, chunks.get(hash).map(Vec::as_slice)) {
+            ContentState::Intact(len) if len != attr.size => issues.push(Issue::SizeMismatch {
+                ino: attr.ino,
+                expected: attr.size,
+                actual: len,
+            }),
+            ContentState::Intact(_) => {}
+            ContentState::Missing { blob } => issues.push(Issue::MissingContent {
+                ino: attr.ino,
+                hash: hash.clone(),
+                blob,
+            }),
+            ContentState::Corrupt { blob, detail } => issues.push(Issue::CorruptContent {
+                ino: attr.ino,
+                hash: hash.clone(),
+                blob,
+                detail,
+            }),
+        }
+    }
+
+    let mut orphaned: Vec<_> = inode_to_hash
+        .iter()
+        .filter(|(ino, _)| !file_attr.contains_key(ino))
+        .collect();
+    orphaned.sort();
+    for (&ino, hash) in orphaned {
+        issues.push(Issue::ContentWithoutInode {
+            ino,
+            hash: hash.clone(),
+        });
+    }
+
+    if !file_attr.contains_key(&FUSE_ROOT_ID) {
+        issues.push(Issue::MissingRoot);
+    }
+
+    let mut parents: Vec<_> = directory.iter().collect();
+    parents.sort_by_key(|(&parent, _)| parent);
+    for (&parent, children) in parents {
+        if file_attr
+            .get(&parent)
+            .is_none_or(|attr| attr.kind != FileType::Directory)
+        {
+            issues.push(Issue::EntriesOfNonDirectory { ino: parent });
+        }
+
+        let mut children: Vec<_> = children.iter().collect();
+        children.sort();
+        for (name, &ino) in children {
+            if !file_attr.contains_key(&ino) {
+                issues.push(Issue::DanglingEntry {
+                    parent,
+                    name: name.clone(),
+                    ino,
+                });
+            }
+        }
+    }
+
+    let reachable = inode_paths(directory);
+    let mut unreachable: Vec<_> = file_attr
+        .keys()
+        .filter(|ino| !reachable.contains_key(ino))
+        .collect();
+    unreachable.sort();
+    for &ino in unreachable {
+        issues.push(Issue::Unreachable { ino });
+    }
+
+    issues
+}
+
+/// Path of every inode reachable from the root, relative to the root
+pub fn inode_paths(directory: &HashMap<u64, HashMap<OsString, u64>>) -> HashMap<u64, PathBuf> {
+    let mut paths = HashMap::from([(FUSE_ROOT_ID, PathBuf::new())]);
+    let mut queue = VecDeque::from([FUSE_ROOT_ID]);
+
+    while let Some(parent) = queue.pop_front() {
+        let Some(children) = directory.get(&parent) else {
+            continue;
+        };
+
+        let parent_path = paths[&parent].clone();
+        for (name, &ino) in children {
+            if let Entry::Vacant(entry) = paths.entry(ino) {
+                entry.insert(parent_path.join(name));
+                queue.push_back(ino);
+            }
+        }
+    }
+
+    paths
+}
+
+/// Re-hash a stored blob, returning its hash and length, or `None` if it is
+/// not stored. Its content is fed to `file` as well when it is a chunk of a
+/// file being checked.
+fn hash_blob(
+    folder: &Path,
+    hash: &str,
+    mut file: Option<&mut Sha256>,
+) -> io::Result<Option<(String, u64)>> {
+    let mut reader = match blobs::open(folder, hash)? {
+        Some(reader) => reader,
+        None => return Ok(None),
+    };
+
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; READ_BUF_SIZE];
+    let mut len = 0;
+
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        if let Some(file) = file.as_mut() {
+            file.update(&buf[..n]);
+        }
+        len += n as u64;
+    }
+
+    Ok(Some((format!("{:x}", hasher.finalize()), len)))
+}
@@ -3,14 +3,21 @@ mod db;
 mod grpc;
 mod http;
 mod filesystem;
+mod protocol;
 
 use actix_web::{web, App, HttpServer};
 use clap::Command;
 use colored::*;
 use db::init_db;
+use filesystem::disk_cache::DiskCacheConfig;
+use filesystem::pool::PoolConfig;
+use filesystem::registry::MountRegistry;
 use filesystem::silofs::SiloFS;
 use grpc::{silo::silo_server::SiloServer, TheSilo};
 use http::{configure_routes, AppState};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
 use tonic::transport::Server;
 
@@ -39,6 +46,64 @@ async fn main() -> std::io::Result<()> {
                         .long("db")
                         .help("The path to the SQLite database file")
                         .default_value("./data/silo.db"),
+                    clap::Arg::new("indexer")
+                        .long("indexer")
+                        .help("The indexer address, repeat or separate with commas to add fallbacks")
+                        .action(clap::ArgAction::Append)
+                        .value_delimiter(',')
+                        .default_value("127.0.0.1:8080"),
+                    clap::Arg::new("pool")
+                        .long("indexer-pool-size")
+                        .help("The maximum number of concurrent connections to the indexer")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("8"),
+                    clap::Arg::new("timeout")
+                        .long("indexer-timeout-ms")
+                        .help("The timeout in milliseconds for a single request to the indexer")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("30000"),
+                    clap::Arg::new("cache_dir")
+                        .long("cache-dir")
+                        .help("The directory to cache image files in across restarts")
+                        .default_value("./data/cache"),
+                    clap::Arg::new("cache_size")
+                        .long("cache-size-mb")
+                        .help("The maximum size of the file cache in megabytes")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("10240"),
+                    clap::Arg::new("memory_cache_size")
+                        .long("memory-cache-mb")
+                        .help("The maximum size in megabytes of file contents kept in memory")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("1024"),
+                    clap::Arg::new("retries")
+                        .long("indexer-retries")
+                        .help("The number of times a request is retried when the indexer connection drops")
+                        .value_parser(clap::value_parser!(u32))
+                        .default_value("5"),
+                    clap::Arg::new("compression")
+                        .long("indexer-compression")
+                        .help("Whether to ask the indexer to compress file contents and metadata it sends")
+                        .value_parser(clap::value_parser!(bool))
+                        .default_value("true"),
+                    clap::Arg::new("verify")
+                        .long("verify-content")
+                        .help("Whether to check file contents received from the indexer against their hash")
+                        .value_parser(clap::value_parser!(bool))
+                        .default_value("true"),
+                    clap::Arg::new("mount_dir")
+                        .long("mount-dir")
+                        .help("The directory to mount images for containers in")
+                        .default_value("/tmp/silo"),
+                    clap::Arg::new("scratch_dir")
+                        .long("scratch-dir")
+                        .help("The directory to keep the files containers write in while they run")
+                        .default_value("./data/scratch"),
+                    clap::Arg::new("keep_writes")
+                        .long("keep-writes")
+                        .help("Whether to keep the files a container wrote in the scratch directory after it exits")
+                        .value_parser(clap::value_parser!(bool))
+                        .default_value("false"),
                 ]),
         )
        
@@ -49,14 +114,43 @@ async fn main() -> std::io::Result<()> {
             let grpc_port: String = sub_matches.get_one::<String>("gp").unwrap().clone();
             let http_port: String = sub_matches.get_one::<String>("hp").unwrap().clone();
             let db_path: String = sub_matches.get_one::<String>("db").unwrap().clone();
+            let indexer_addrs: Vec<String> = sub_matches
+                .get_many::<String>("indexer")
+                .unwrap()
+                .cloned()
+                .collect();
+            let pool_config = PoolConfig {
+                size: *sub_matches.get_one::<usize>("pool").unwrap(),
+                request_timeout: Duration::from_millis(
+                    *sub_matches.get_one::<u64>("timeout").unwrap(),
+                ),
+                max_retries: *sub_matches.get_one::<u32>("retries").unwrap(),
+                compression: *sub_matches.get_one::<bool>("compression").unwrap(),
+                ..PoolConfig::default()
+            };
+            let cache_config = DiskCacheConfig {
+                path: PathBuf::from(sub_matches.get_one::<String>("cache_dir").unwrap()),
+                max_bytes: *sub_matches.get_one::<u64>("cache_size").unwrap() * 1024 * 1024,
+                max_blob_size: 8 * 1024 * 1024,
+            };
+            let memory_cache_bytes =
+                *sub_matches.get_one::<u64>("memory_cache_size").unwrap() * 1024 * 1024;
+            let verify_content = *sub_matches.get_one::<bool>("verify").unwrap();
+            let scratch_dir = PathBuf::from(sub_matches.get_one::<String>("scratch_dir").unwrap());
+            let keep_writes = *sub_matches.get_one::<bool>("keep_writes").unwrap();
+            let mount_dir = PathBuf::from(sub_matches.get_one::<String>("mount_dir").unwrap());
 
             let grpc_server_addr: String = format!("0.0.0.0:{}", grpc_port);
             let http_server_addr = format!("0.0.0.0:{}", &http_port);
             
             let conn = init_db(db_path).expect("Failed to connect to the database");
 
+            // Cleans up the mounts of containers left running by a previous run
+            let mounts = Arc::new(MountRegistry::new(mount_dir, scratch_dir, keep_writes)?);
+
             let app_state = web::Data::new(AppState {
                 db_connection: Mutex::new(conn),
+                mounts: mounts.clone(),
             });
 
             let http_server = HttpServer::new(move || {
@@ -76,7 +170,14 @@ async fn main() -> std::io::Result<()> {
             let grpc_server = Server::builder()
                 .add_service(SiloServer::new(TheSilo {
                     host_link: format!("http://{}", http_server_addr),
-                    filesystem: SiloFS::new("127.0.0.1:8080")?
+                    verify_content,
+                    filesystem: Arc::new(SiloFS::new(
+                        indexer_addrs,
+                        pool_config,
+                        cache_config,
+                        memory_cache_bytes,
+                    )?),
+                    mounts,
                 }))
                 .serve(grpc_server_addr.parse().unwrap());
 
@@ -0,0 +1,802 @@
+//! Framed binary protocol spoken between SiloFS and the indexer's TCP server.
+//!
+//! Every message on the wire is a frame:
+//!
+//! ```text
+//! +-------------+------------+-----------------+---------+
+//! | len: u64 BE | opcode: u8 | request_id: u32 | payload |
+//! +-------------+------------+-----------------+---------+
+//! ```
+//!
+//! `len` counts the bytes following the length field. A connection starts with
+//! the client sending a `Hello` frame carrying its protocol version and the
+//! optional features it can use; the server answers with its own `Hello`
+//! carrying the features enabled for the connection, or with an `Error` frame
+//! and closes the connection if it does not understand that version.
+//!
+//! When `FEATURE_ZSTD` is enabled, the payloads of `ImageData`, `Content` and
+//! `Range` frames start with an encoding byte, see `encode_payload`.
+//!
+//! This module is shared by the `silo` and `indexer` binaries, each of which
+//! only uses its own side of the protocol.
+#![allow(dead_code)]
+
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStringExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Version of the protocol implemented by this build. Version 4 sends the
+/// chunk lists of chunked files with the image data, which older clients
+/// cannot read those files without. Version 5 adds features to `Hello`.
+/// Version 6 sends directory entry names as raw bytes, so names that are not
+/// valid UTF-8 survive. Version 7 sends them as `EntryName`s, which keep
+/// valid UTF-8 names as strings.
+pub const PROTOCOL_VERSION: u16 = 7;
+
+/// Oldest protocol version this build still understands
+pub const MIN_PROTOCOL_VERSION: u16 = 7;
+
+/// Feature bit for zstd-compressed payloads
+pub const FEATURE_ZSTD: u32 = 1 << 0;
+
+/// Payloads smaller than this are never worth compressing
+const MIN_COMPRESSED_PAYLOAD: usize = 512;
+
+/// zstd level used for payloads compressed on the fly, favouring speed
+const PAYLOAD_COMPRESSION_LEVEL: i32 = 1;
+
+/// Encoding byte of a payload sent as is
+const ENCODING_RAW: u8 = 0;
+
+/// Encoding byte of a zstd-compressed payload
+const ENCODING_ZSTD: u8 = 1;
+
+/// Size of the opcode and request id that follow the length field
+const HEADER_LEN: u64 = 1 + 4;
+
+/// Upper bound on the length of a single ranged read
+pub const MAX_RANGE_LEN: u32 = 16 * 1024 * 1024;
+
+/// Upper bound on the number of inodes in an access trace
+pub const MAX_TRACE_LEN: usize = 20_000;
+
+/// Upper bound on the payload of frames carrying a name, a hash or a short
+/// message, which is every request but `PutTrace`
+const MAX_REQUEST_LEN: u64 = 4 * 1024;
+
+/// Upper bound on the payload of `Range` and `Content` frames: at most
+/// `MAX_RANGE_LEN` bytes, with room for the encoding byte and zstd framing
+const MAX_DATA_LEN: u64 = MAX_RANGE_LEN as u64 + 128 * 1024;
+
+/// Upper bound on the payload of `PutTrace` and `Trace` frames
+const MAX_TRACE_PAYLOAD_LEN: u64 = 2 + MAX_REQUEST_LEN + 8 * MAX_TRACE_LEN as u64;
+
+/// Upper bound on the payload of `ImageData` frames, the serialized metadata
+/// of a whole image
+const MAX_IMAGE_DATA_LEN: u64 = 1 << 30;
+
+/// Operation carried by a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Opcode {
+    /// Version handshake, sent by both sides
+    Hello = 0x01,
+    /// Request the metadata of an image, payload is the image name
+    GetImageData = 0x02,
+    /// Request a whole blob, payload is its hash
+    GetContent = 0x03,
+    /// Request a byte range of a blob, see `encode_range_request`
+    ReadRange = 0x04,
+    /// Store the access trace of an image, see `encode_trace`
+    PutTrace = 0x05,
+    /// Request the access trace of an image, payload is the image name
+    GetTrace = 0x06,
+    /// Response to `GetImageData`, payload is the serialized metadata
+    ImageData = 0x82,
+    /// Response to `GetContent`, payload is the blob
+    Content = 0x83,
+    /// Response to `ReadRange`, payload is the requested bytes, which may be
+    /// shorter than asked for at the end of the blob
+    Range = 0x84,
+    /// Response to `PutTrace`, with an empty payload
+    TraceSaved = 0x85,
+    /// Response to `GetTrace`, payload is the inodes as `u64 BE`, empty if
+    /// no trace was recorded yet
+    Trace = 0x86,
+    /// Error response, payload is an `ErrorCode` followed by a message
+    Error = 0xff,
+}
+
+impl TryFrom<u8> for Opcode {
+    type Error = io::Error;
+
+    fn try_from(value: u8) -> io::Result<Self> {
+        match value {
+            0x01 => Ok(Opcode::Hello),
+            0x02 => Ok(Opcode::GetImageData),
+            0x03 => Ok(Opcode::GetContent),
+            0x04 => Ok(Opcode::ReadRange),
+            0x05 => Ok(Opcode::PutTrace),
+            0x06 => Ok(Opcode::GetTrace),
+            0x82 => Ok(Opcode::ImageData),
+            0x83 => Ok(Opcode::Content),
+            0x84 => Ok(Opcode::Range),
+            0x85 => Ok(Opcode::TraceSaved),
+            0x86 => Ok(Opcode::Trace),
+            0xff => Ok(Opcode::Error),
+            other => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown opcode {:#04x}", other),
+            )),
+        }
+    }
+}
+
+impl Opcode {
+    /// Largest payload a frame with this opcode may carry. Frames are read
+    /// incrementally, so a peer announcing a bogus length only gets as much
+    /// memory as it actually sends, up to this bound.
+    pub fn max_payload_len(self) -> u64 {
+        match self {
+            Opcode::ImageData => MAX_IMAGE_DATA_LEN,
+            Opcode::Content | Opcode::Range => MAX_DATA_LEN,
+            Opcode::PutTrace | Opcode::Trace => MAX_TRACE_PAYLOAD_LEN,
+            Opcode::Hello
+            | Opcode::GetImageData
+            | Opcode::GetContent
+            | Opcode::ReadRange
+            | Opcode::GetTrace
+            | Opcode::TraceSaved
+            | Opcode::Error => MAX_REQUEST_LEN,
+        }
+    }
+
+    /// Largest payload a frame with this opcode may carry once decoded by
+    /// `decode_payload`
+    pub fn max_decoded_len(self) -> u64 {
+        match self {
+            Opcode::Content | Opcode::Range => MAX_RANGE_LEN as u64,
+            other => other.max_payload_len(),
+        }
+    }
+}
+
+/// Reason carried by an `Error` frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum ErrorCode {
+    UnsupportedVersion = 1,
+    BadRequest = 2,
+    NotFound = 3,
+    Internal = 4,
+}
+
+impl From<u16> for ErrorCode {
+    fn from(value: u16) -> Self {
+        match value {
+            1 => ErrorCode::UnsupportedVersion,
+            2 => ErrorCode::BadRequest,
+            3 => ErrorCode::NotFound,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+impl From<ErrorCode> for io::ErrorKind {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::UnsupportedVersion => io::ErrorKind::Unsupported,
+            ErrorCode::BadRequest => io::ErrorKind::InvalidInput,
+            ErrorCode::NotFound => io::ErrorKind::NotFound,
+            ErrorCode::Internal => io::ErrorKind::Other,
+        }
+    }
+}
+
+/// Check whether a peer's protocol version can be served
+pub fn is_supported_version(version: u16) -> bool {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+}
+
+/// Parse the protocol version and feature bits carried by a `Hello`
+/// payload: `version: u16 BE | features: u32 BE`
+pub fn parse_hello(payload: &[u8]) -> io::Result<(u16, u32)> {
+    match payload {
+        [v0, v1, f0, f1, f2, f3] => Ok((
+            u16::from_be_bytes([*v0, *v1]),
+            u32::from_be_bytes([*f0, *f1, *f2, *f3]),
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Malformed hello frame",
+        )),
+    }
+}
+
+/// Prefix a payload with its encoding, compressing it when that makes it
+/// smaller
+pub fn encode_payload(data: Vec<u8>) -> Vec<u8> {
+    if data.len() >= MIN_COMPRESSED_PAYLOAD {
+        if let Ok(compressed) = zstd::bulk::compress(&data, PAYLOAD_COMPRESSION_LEVEL) {
+            if compressed.len() < data.len() {
+                return encode_compressed(&compressed);
+            }
+        }
+    }
+
+    let mut payload = Vec::with_capacity(1 + data.len());
+    payload.push(ENCODING_RAW);
+    payload.extend_from_slice(&data);
+    payload
+}
+
+/// Prefix data that is already a zstd frame with its encoding
+pub fn encode_compressed(compressed: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(1 + compressed.len());
+    payload.push(ENCODING_ZSTD);
+    payload.extend_from_slice(compressed);
+    payload
+}
+
+/// Decode a payload built by `encode_payload` or `encode_compressed`,
+/// failing if it decodes to more than `max_len` bytes
+pub fn decode_payload(payload: &[u8], max_len: u64) -> io::Result<Vec<u8>> {
+    let too_long = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Payload decodes to more than {} bytes", max_len),
+        )
+    };
+
+    match payload.split_first() {
+        Some((&ENCODING_RAW, data)) if data.len() as u64 > max_len => Err(too_long()),
+        Some((&ENCODING_RAW, data)) => Ok(data.to_vec()),
+        Some((&ENCODING_ZSTD, data)) => {
+            // Stop one byte past the limit, a small frame can expand to far
+            // more than any frame read off the wire
+            let mut decoded = Vec::new();
+            zstd::stream::read::Decoder::new(data)?
+                .take(max_len + 1)
+                .read_to_end(&mut decoded)?;
+            if decoded.len() as u64 > max_len {
+                return Err(too_long());
+            }
+            Ok(decoded)
+        }
+        Some((encoding, _)) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unknown payload encoding {}", encoding),
+        )),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Missing payload encoding",
+        )),
+    }
+}
+
+/// Name of a directory entry in the serialized image metadata: a string when
+/// it is valid UTF-8, as nearly every name is, and its raw bytes otherwise,
+/// which JSON spells as a several times longer array of numbers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EntryName {
+    Utf8(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<OsString> for EntryName {
+    fn from(name: OsString) -> Self {
+        match name.into_string() {
+            Ok(name) => EntryName::Utf8(name),
+            Err(name) => EntryName::Bytes(name.into_vec()),
+        }
+    }
+}
+
+impl From<EntryName> for OsString {
+    fn from(name: EntryName) -> Self {
+        match name {
+            EntryName::Utf8(name) => OsString::from(name),
+            EntryName::Bytes(name) => OsString::from_vec(name),
+        }
+    }
+}
+
+/// Build a `ReadRange` payload: `offset: u64 BE | length: u32 BE | hash`
+pub fn encode_range_request(hash: &str, offset: u64, length: u32) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(12 + hash.len());
+    payload.extend_from_slice(&offset.to_be_bytes());
+    payload.extend_from_slice(&length.to_be_bytes());
+    payload.extend_from_slice(hash.as_bytes());
+    payload
+}
+
+/// Split a `ReadRange` payload into its hash, offset and length
+pub fn parse_range_request(payload: &[u8]) -> io::Result<(&[u8], u64, u32)> {
+    if payload.len() < 12 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Malformed range request",
+        ));
+    }
+    let (offset, rest) = payload.split_at(8);
+    let (length, hash) = rest.split_at(4);
+    Ok((
+        hash,
+        u64::from_be_bytes(offset.try_into().unwrap()),
+        u32::from_be_bytes(length.try_into().unwrap()),
+    ))
+}
+
+/// Encode a list of inodes as consecutive `u64 BE`
+pub fn encode_inodes(inodes: &[u64]) -> Vec<u8> {
+    inodes.iter().flat_map(|ino| ino.to_be_bytes()).collect()
+}
+
+/// Decode a list of inodes encoded by `encode_inodes`
+pub fn parse_inodes(payload: &[u8]) -> io::Result<Vec<u64>> {
+    if !payload.len().is_multiple_of(8) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Malformed inode list",
+        ));
+    }
+    Ok(payload
+        .chunks_exact(8)
+        .map(|chunk| u64::from_be_bytes(chunk.try_into().unwrap()))
+        .collect())
+}
+
+/// Build a `PutTrace` payload: `name_len: u16 BE | image name | inodes`
+pub fn encode_trace(image_name: &str, inodes: &[u64]) -> io::Result<Vec<u8>> {
+    let name_len = u16::try_from(image_name.len()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Image name of {} bytes is too long", image_name.len()),
+        )
+    })?;
+
+    let mut payload = Vec::with_capacity(2 + image_name.len() + inodes.len() * 8);
+    payload.extend_from_slice(&name_len.to_be_bytes());
+    payload.extend_from_slice(image_name.as_bytes());
+    payload.extend_from_slice(&encode_inodes(inodes));
+    Ok(payload)
+}
+
+/// Split a `PutTrace` payload into its image name and inodes
+pub fn parse_trace(payload: &[u8]) -> io::Result<(String, Vec<u64>)> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "Malformed trace");
+
+    if payload.len() < 2 {
+        return Err(malformed());
+    }
+    let (name_len, rest) = payload.split_at(2);
+    let name_len = u16::from_be_bytes([name_len[0], name_len[1]]) as usize;
+    if rest.len() < name_len {
+        return Err(malformed());
+    }
+    let (name, inodes) = rest.split_at(name_len);
+    let name = String::from_utf8(name.to_vec()).map_err(|_| malformed())?;
+
+    Ok((name, parse_inodes(inodes)?))
+}
+
+/// A single protocol message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub opcode: Opcode,
+    pub request_id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(opcode: Opcode, request_id: u32, payload: Vec<u8>) -> Self {
+        Self {
+            opcode,
+            request_id,
+            payload,
+        }
+    }
+
+    /// Handshake frame announcing `PROTOCOL_VERSION` and `features`
+    pub fn hello(request_id: u32, features: u32) -> Self {
+        let mut payload = Vec::with_capacity(6);
+        payload.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        payload.extend_from_slice(&features.to_be_bytes());
+        Self::new(Opcode::Hello, request_id, payload)
+    }
+
+    /// Error frame answering `request_id`, with the message cut short if it
+    /// does not fit in the frame
+    pub fn error(request_id: u32, code: ErrorCode, message: &str) -> Self {
+        let mut end = message.len().min(MAX_REQUEST_LEN as usize - 2);
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        let message = &message[..end];
+
+        let mut payload = Vec::with_capacity(2 + message.len());
+        payload.extend_from_slice(&(code as u16).to_be_bytes());
+        payload.extend_from_slice(message.as_bytes());
+        Self::new(Opcode::Error, request_id, payload)
+    }
+
+    /// Convert a response into its payload, turning `Error` frames and
+    /// unexpected opcodes into `io::Error`s
+    pub fn into_payload(self, expected: Opcode) -> io::Result<Vec<u8>> {
+        if self.opcode == Opcode::Error {
+            let (code, message) = if self.payload.len() >= 2 {
+                (
+                    ErrorCode::from(u16::from_be_bytes([self.payload[0], self.payload[1]])),
+                    String::from_utf8_lossy(&self.payload[2..]).into_owned(),
+                )
+            } else {
+                (ErrorCode::Internal, String::from("malformed error frame"))
+            };
+            return Err(io::Error::new(
+                code.into(),
+                format!("Indexer error ({:?}): {}", code, message),
+            ));
+        }
+
+        if self.opcode != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Expected {:?} frame, got {:?}", expected, self.opcode),
+            ));
+        }
+
+        Ok(self.payload)
+    }
+
+    fn header(&self) -> [u8; 13] {
+        let mut header = [0u8; 13];
+        let len = HEADER_LEN + self.payload.len() as u64;
+        header[..8].copy_from_slice(&len.to_be_bytes());
+        header[8] = self.opcode as u8;
+        header[9..].copy_from_slice(&self.request_id.to_be_bytes());
+        header
+    }
+
+    fn from_parts(len: u64, rest: &[u8; 5]) -> io::Result<(Opcode, u32, u64)> {
+        let opcode = Opcode::try_from(rest[0])?;
+        if !(HEADER_LEN..=HEADER_LEN + opcode.max_payload_len()).contains(&len) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid length {} for a {:?} frame", len, opcode),
+            ));
+        }
+        let request_id = u32::from_be_bytes([rest[1], rest[2], rest[3], rest[4]]);
+        Ok((opcode, request_id, len - HEADER_LEN))
+    }
+
+    /// Check the payload fits in a frame of its opcode before sending it, as
+    /// the peer would drop the connection on reading it
+    pub fn check_len(&self) -> io::Result<()> {
+        if self.payload.len() as u64 > self.opcode.max_payload_len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Payload of {} bytes exceeds the {} byte limit of {:?} frames",
+                    self.payload.len(),
+                    self.opcode.max_payload_len(),
+                    self.opcode
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_payload_read(payload: &[u8], payload_len: u64) -> io::Result<()> {
+        if payload.len() as u64 != payload_len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "Connection closed after {} of {} payload bytes",
+                    payload.len(),
+                    payload_len
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Write the frame to a blocking stream
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.check_len()?;
+        writer.write_all(&self.header())?;
+        writer.write_all(&self.payload)?;
+        writer.flush()
+    }
+
+    /// Read a frame from a blocking stream
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut len_buf = [0u8; 8];
+        reader.read_exact(&mut len_buf)?;
+        let mut rest = [0u8; 5];
+        reader.read_exact(&mut rest)?;
+
+        let (opcode, request_id, payload_len) =
+            Self::from_parts(u64::from_be_bytes(len_buf), &rest)?;
+
+        let mut payload = Vec::new();
+        reader.take(payload_len).read_to_end(&mut payload)?;
+        Self::check_payload_read(&payload, payload_len)?;
+
+        Ok(Self::new(opcode, request_id, payload))
+    }
+
+    /// Write the frame to an async stream
+    pub async fn write_to_async<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> io::Result<()> {
+        self.check_len()?;
+        writer.write_all(&self.header()).await?;
+        writer.write_all(&self.payload).await?;
+        writer.flush().await
+    }
+
+    /// Read a frame from an async stream, returning `None` if the peer closed
+    /// the connection cleanly between frames
+    pub async fn read_from_async<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Self>> {
+        let mut len_buf = [0u8; 8];
+        let first = reader.read(&mut len_buf).await?;
+        if first == 0 {
+            return Ok(None);
+        }
+        reader.read_exact(&mut len_buf[first..]).await?;
+        let mut rest = [0u8; 5];
+        reader.read_exact(&mut rest).await?;
+
+        let (opcode, request_id, payload_len) =
+            Self::from_parts(u64::from_be_bytes(len_buf), &rest)?;
+
+        let mut payload = Vec::new();
+        reader.take(payload_len).read_to_end(&mut payload).await?;
+        Self::check_payload_read(&payload, payload_len)?;
+
+        Ok(Some(Self::new(opcode, request_id, payload)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(frame: &Frame) -> Vec<u8> {
+        let mut buf = Vec::new();
+        frame.write_to(&mut buf).unwrap();
+        buf
+    }
+
+    /// Header announcing `payload_len` bytes of payload
+    fn header(payload_len: u64, opcode: u8) -> Vec<u8> {
+        let mut buf = (HEADER_LEN + payload_len).to_be_bytes().to_vec();
+        buf.push(opcode);
+        buf.extend_from_slice(&7u32.to_be_bytes());
+        buf
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let frames = [
+            Frame::hello(1, FEATURE_ZSTD),
+            Frame::new(Opcode::ReadRange, 2, encode_range_request("abc", 4, 5)),
+            Frame::new(Opcode::Content, u32::MAX, vec![0xab; 100_000]),
+            Frame::new(Opcode::TraceSaved, 3, Vec::new()),
+        ];
+
+        let mut buf = Vec::new();
+        for frame in &frames {
+            frame.write_to(&mut buf).unwrap();
+        }
+
+        let mut reader = buf.as_slice();
+        for frame in &frames {
+            assert_eq!(&Frame::read_from(&mut reader).unwrap(), frame);
+        }
+        assert!(reader.is_empty());
+    }
+
+    #[tokio::test]
+    async fn frame_round_trip_async() {
+        let frames = [
+            Frame::new(Opcode::GetImageData, 1, b"alpine:latest".to_vec()),
+            Frame::new(Opcode::Range, 2, vec![7; 70_000]),
+        ];
+
+        let mut buf = Vec::new();
+        for frame in &frames {
+            frame.write_to_async(&mut buf).await.unwrap();
+        }
+
+        let mut reader = buf.as_slice();
+        for frame in &frames {
+            let read = Frame::read_from_async(&mut reader).await.unwrap();
+            assert_eq!(read.as_ref(), Some(frame));
+        }
+        // Closed cleanly between frames
+        assert!(Frame::read_from_async(&mut reader).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_unknown_opcode() {
+        let err = Frame::read_from(&mut header(0, 0x42).as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_length_over_opcode_cap() {
+        // Nothing past the header is read, so the payload need not be there
+        let len = Opcode::GetContent.max_payload_len() + 1;
+        let err =
+            Frame::read_from(&mut header(len, Opcode::GetContent as u8).as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let len = Opcode::ImageData.max_payload_len() + 1;
+        let err =
+            Frame::read_from(&mut header(len, Opcode::ImageData as u8).as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Shorter than the header itself
+        let mut buf = 4u64.to_be_bytes().to_vec();
+        buf.extend_from_slice(&[Opcode::Hello as u8, 0, 0, 0, 0]);
+        let err = Frame::read_from(&mut buf.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn rejects_short_payload() {
+        let mut buf = encoded(&Frame::new(Opcode::Content, 1, vec![1; 64]));
+        buf.truncate(buf.len() - 10);
+
+        let err = Frame::read_from(&mut buf.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let err = Frame::read_from_async(&mut buf.as_slice())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn refuses_to_write_oversized_payload() {
+        let frame = Frame::new(Opcode::GetContent, 1, vec![0; MAX_REQUEST_LEN as usize + 1]);
+        let mut buf = Vec::new();
+        let err = frame.write_to(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn error_frame_into_payload() {
+        let err = Frame::error(1, ErrorCode::NotFound, "no such blob")
+            .into_payload(Opcode::Content)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("no such blob"));
+
+        let err = Frame::new(Opcode::Error, 1, vec![0])
+            .into_payload(Opcode::Content)
+            .unwrap_err();
+        assert!(err.to_string().contains("malformed error frame"));
+
+        let err = Frame::new(Opcode::Range, 1, Vec::new())
+            .into_payload(Opcode::Content)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn error_frame_truncates_long_message() {
+        // Multi-byte characters, so the limit falls inside one
+        let message = "é".repeat(MAX_REQUEST_LEN as usize);
+        let frame = Frame::error(1, ErrorCode::Internal, &message);
+        assert!(frame.check_len().is_ok());
+        assert!(std::str::from_utf8(&frame.payload[2..]).is_ok());
+
+        let read = Frame::read_from(&mut encoded(&frame).as_slice()).unwrap();
+        assert_eq!(read, frame);
+    }
+
+    #[test]
+    fn range_request_bounds() {
+        let payload = encode_range_request("hash", u64::MAX, MAX_RANGE_LEN);
+        let (hash, offset, length) = parse_range_request(&payload).unwrap();
+        assert_eq!(
+            (hash, offset, length),
+            (&b"hash"[..], u64::MAX, MAX_RANGE_LEN)
+        );
+
+        // An empty hash still parses, the server rejects it as unknown
+        let payload = encode_range_request("", 0, 0);
+        assert_eq!(parse_range_request(&payload).unwrap(), (&b""[..], 0, 0));
+
+        assert!(parse_range_request(&payload[..11]).is_err());
+        assert!(parse_range_request(&[]).is_err());
+    }
+
+    #[test]
+    fn trace_round_trip() {
+        let inodes = [1, 42, u64::MAX];
+        let payload = encode_trace("alpine:latest", &inodes).unwrap();
+        assert_eq!(
+            parse_trace(&payload).unwrap(),
+            (String::from("alpine:latest"), inodes.to_vec())
+        );
+
+        // Name longer than the payload
+        assert!(parse_trace(&[0, 10, b'a']).is_err());
+        // Inodes not a multiple of 8 bytes
+        assert!(parse_trace(&payload[..payload.len() - 1]).is_err());
+        assert!(parse_trace(&[0]).is_err());
+        assert!(parse_trace(&[0, 1, 0xff]).is_err());
+
+        let err = encode_trace(&"a".repeat(u16::MAX as usize + 1), &inodes).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn inodes_round_trip() {
+        assert_eq!(parse_inodes(&encode_inodes(&[3, 2, 1])).unwrap(), [3, 2, 1]);
+        assert!(parse_inodes(&[]).unwrap().is_empty());
+        assert!(parse_inodes(&[0; 9]).is_err());
+    }
+
+    #[test]
+    fn payload_encoding_round_trip() {
+        for data in [Vec::new(), vec![1, 2, 3], vec![0; 4096]] {
+            assert_eq!(
+                decode_payload(&encode_payload(data.clone()), 4096).unwrap(),
+                data
+            );
+        }
+        assert!(decode_payload(&[], 4096).is_err());
+        assert!(decode_payload(&[9, 1, 2], 4096).is_err());
+    }
+
+    #[test]
+    fn rejects_payload_decoding_past_limit() {
+        let max_len = Opcode::Range.max_decoded_len();
+        let payload = encode_payload(vec![0; max_len as usize]);
+        // Compressed far below the limit on the frame itself
+        assert!((payload.len() as u64) < Opcode::GetContent.max_payload_len());
+        assert_eq!(
+            decode_payload(&payload, max_len).unwrap().len() as u64,
+            max_len
+        );
+
+        let payload = encode_payload(vec![0; max_len as usize + 1]);
+        let err = decode_payload(&payload, max_len).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let err = decode_payload(&[ENCODING_RAW, 1, 2, 3], 2).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn parses_hello() {
+        assert_eq!(
+            parse_hello(&Frame::hello(1, FEATURE_ZSTD).payload).unwrap(),
+            (PROTOCOL_VERSION, FEATURE_ZSTD)
+        );
+        assert!(parse_hello(&[0, 1]).is_err());
+        assert!(is_supported_version(PROTOCOL_VERSION));
+        assert!(!is_supported_version(PROTOCOL_VERSION + 1));
+    }
+
+    #[test]
+    fn entry_name_round_trip() {
+        let utf8 = EntryName::from(OsString::from("etc"));
+        assert_eq!(serde_json::to_string(&utf8).unwrap(), "\"etc\"");
+
+        let bytes = EntryName::from(OsString::from_vec(vec![b'a', 0xff]));
+        let json = serde_json::to_string(&bytes).unwrap();
+        assert_eq!(json, "[97,255]");
+
+        for (name, json) in [(utf8, "\"etc\""), (bytes, "[97,255]")] {
+            let read: EntryName = serde_json::from_str(json).unwrap();
+            assert_eq!(OsString::from(read), OsString::from(name));
+        }
+    }
+}